@@ -1,18 +1,116 @@
-use std::{io::{Cursor, Read}, path::Path};
+use std::{fmt::{self, Display, Formatter}, io::{Cursor, Seek, Write}, ops::Range, path::Path, time::Duration};
 
 use byteorder::ReadBytesExt;
 use hound::{WavSpec, WavWriter};
 use speex_safe::{NbMode, NbSubmodeId, SpeexBits, SpeexDecoder};
 
-use crate::{submode_bits_per_frame, SAMPLE_COUNT};
+use crate::{is_std_stream, submode_bits_per_frame, write_output, CODEC_DELAY, SAMPLE_COUNT, SPEEX_SAMPLE_RATE};
 
-pub fn decode(data: Vec<u8>, output: &Path) {
-    let mut data = Cursor::new(data);
+// how many plausible frames in a row we want to see before trusting a resync point
+const RESYNC_FRAMES: usize = 3;
+
+#[derive(Debug)]
+pub enum VopError {
+    /// The data ends before the size at the start does
+    NoSize,
+    /// The size at the start doesn't match the actual size
+    SizeMismatch { written: u64, actual: usize },
+    /// The data ends in the middle of the frame at this offset
+    Truncated(usize),
+    /// Speex couldn't decode the frame at this offset
+    Decode(usize),
+}
+
+impl Display for VopError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSize => write!(f, "vop data is too short to contain a size"),
+            Self::SizeMismatch { written, actual } => {
+                write!(f, "size written in vop data is {written} bytes, but it's actually {actual} bytes")
+            }
+            Self::Truncated(offset) => write!(f, "vop data ends in the middle of the frame at {offset:#x}"),
+            Self::Decode(offset) => write!(f, "can't decode the frame at {offset:#x}"),
+        }?;
+        write!(f, ", this is probably corrupted (--salvage might get something out of it)")
+    }
+}
+
+impl std::error::Error for VopError {}
+
+pub struct Frame<'a> {
+    /// Offset of the flags byte in the vop data
+    pub offset: usize,
+    pub flags: u8,
+    pub data: &'a [u8],
+}
+
+impl Frame<'_> {
+    pub fn submode(&self) -> NbSubmodeId {
+        let op = self.flags & 0x3F;
+        NbSubmodeId::from(op.clamp(1, 8) as i32)
+    }
+
+    /// Total size of the frame in the vop data, including the flags byte
+    fn len(&self) -> usize {
+        self.data.len() + 1
+    }
+
+    // the strict path clamps out of range submodes in the flags, so anything it would decode goes here too
+    fn is_plausible(&self) -> bool {
+        // narrowband frames start with the wideband bit (always 0 here) and the 4-bit submode
+        self.data[0] >> 3 == self.submode() as u8
+    }
+}
+
+fn frame_at(data: &[u8], offset: usize) -> Option<Frame<'_>> {
+    let flags = *data.get(offset)?;
+    let mut frame = Frame { offset, flags, data: &[] };
+
+    let bits_per_frame = submode_bits_per_frame(frame.submode());
+    let bytes_per_frame = (bits_per_frame + 7) >> 3;
+
+    let start = offset + 1;
+    frame.data = data.get(start..start + bytes_per_frame as usize)?;
+
+    Some(frame)
+}
+
+fn plausible_frame_at(data: &[u8], offset: usize) -> Option<Frame<'_>> {
+    frame_at(data, offset).filter(Frame::is_plausible)
+}
+
+// checks if a few plausible frames in a row (or up to the end of the data) start at this offset
+fn is_resync_point(data: &[u8], mut offset: usize) -> bool {
+    for _ in 0..RESYNC_FRAMES {
+        if offset == data.len() {
+            return true;
+        }
+        match plausible_frame_at(data, offset) {
+            Some(frame) => offset += frame.len(),
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Splits vop data into frames.
+///
+/// With `salvage` enabled, damaged parts are skipped instead of returning an error,
+/// and the skipped byte ranges are returned alongside the frames.
+pub fn read_frames(data: &[u8], salvage: bool) -> Result<(Vec<Frame<'_>>, Vec<Range<usize>>), VopError> {
+    let mut cursor = Cursor::new(data);
 
     let mut size: u64 = 0;
     let mut shift = 0;
     loop {
-        let b = data.read_u8().unwrap();
+        let b = match cursor.read_u8() {
+            Ok(b) => b,
+            Err(_) if salvage => {
+                let skipped = 0..data.len();
+                return Ok((Vec::new(), vec![skipped]));
+            }
+            Err(_) => return Err(VopError::NoSize),
+        };
         size |= (b as u64 & 0x7F) << shift;
 
         if (b & 0x80) == 0 {
@@ -22,47 +120,343 @@ pub fn decode(data: Vec<u8>, output: &Path) {
         shift += 7;
     }
 
-    size += data.position();
-    if size != data.get_ref().len() as u64 {
-        panic!("size written in vop data doesn't match actual size, this is probably corrupted");
+    let header_len = cursor.position() as usize;
+    size += header_len as u64;
+    // salvaging goes by the frames, so a wrong size doesn't matter there
+    if size != data.len() as u64 && !salvage {
+        return Err(VopError::SizeMismatch { written: size, actual: data.len() });
+    }
+
+    let mut frames = Vec::new();
+    let mut skipped = Vec::new();
+
+    let mut offset = header_len;
+    while offset < data.len() {
+        if !salvage {
+            let frame = frame_at(data, offset).ok_or(VopError::Truncated(offset))?;
+            offset += frame.len();
+            frames.push(frame);
+            continue;
+        }
+
+        if let Some(frame) = plausible_frame_at(data, offset) {
+            offset += frame.len();
+            frames.push(frame);
+            continue;
+        }
+
+        let damage_start = offset;
+        offset = (damage_start + 1..data.len())
+            .find(|&offset| is_resync_point(data, offset))
+            .unwrap_or(data.len());
+        skipped.push(damage_start..offset);
+    }
+
+    Ok((frames, skipped))
+}
+
+/// A decoded frame of audio
+pub struct DecodedFrame {
+    /// Position of the first sample, frames lost to damage count towards it as well
+    pub position: usize,
+    pub submode: NbSubmodeId,
+    pub speech_detected: bool,
+    /// Samples in the -1.0 to 1.0 range
+    pub samples: [f32; SAMPLE_COUNT],
+}
+
+impl DecodedFrame {
+    pub fn timestamp(&self) -> Duration {
+        Duration::from_secs_f64(self.position as f64 / SPEEX_SAMPLE_RATE as f64)
+    }
+}
+
+/// What salvaging got out of a damaged vop
+pub struct SalvageReport {
+    pub recovered_frames: usize,
+    /// Byte ranges of the vop data that were skipped, sorted by offset
+    pub skipped: Vec<Range<usize>>,
+}
+
+impl Display for SalvageReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for range in &self.skipped {
+            writeln!(f, "skipped bytes {:#x}..{:#x} ({} bytes)", range.start, range.end, range.len())?;
+        }
+        write!(f, "recovered {} frames, skipped {} damaged parts", self.recovered_frames, self.skipped.len())
+    }
+}
+
+/// Decodes vop data one frame at a time
+pub struct VopDecoder<'a> {
+    frames: std::vec::IntoIter<Frame<'a>>,
+    decoder: SpeexDecoder<NbMode>,
+    salvage: bool,
+    skipped: Vec<Range<usize>>,
+    decoded_frames: usize,
+    /// Frames so far, including the ones that couldn't be decoded
+    position_frames: usize,
+    /// Where the next frame would start if nothing was skipped
+    next_offset: Option<usize>,
+}
+
+impl<'a> VopDecoder<'a> {
+    /// With `salvage` enabled, damaged parts are skipped instead of returning an error
+    pub fn new(data: &'a [u8], salvage: bool) -> Result<Self, VopError> {
+        let (frames, skipped) = read_frames(data, salvage)?;
+
+        Ok(Self {
+            frames: frames.into_iter(),
+            decoder: SpeexDecoder::<NbMode>::new(),
+            salvage,
+            skipped,
+            decoded_frames: 0,
+            position_frames: 0,
+            next_offset: None,
+        })
+    }
+
+    /// What was recovered and skipped so far
+    pub fn salvage_report(&self) -> SalvageReport {
+        let mut skipped = self.skipped.clone();
+        skipped.sort_by_key(|range| range.start);
+        SalvageReport { recovered_frames: self.decoded_frames, skipped }
+    }
+}
+
+impl Iterator for VopDecoder<'_> {
+    type Item = Result<DecodedFrame, VopError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut frame_buffer = [0u8; (submode_bits_per_frame(NbSubmodeId::ExtremeHigh) as usize + 7) >> 3];
+        let mut samples = [0f32; SAMPLE_COUNT];
+
+        for frame in self.frames.by_ref() {
+            //let unknown_bit = (frame.flags & 0x40) != 0;
+
+            // there's no telling how many frames a damaged part had, guess from the size of the one after it
+            if let Some(next_offset) = self.next_offset.filter(|next_offset| frame.offset > *next_offset) {
+                let lost = (frame.offset - next_offset) as f64 / frame.len() as f64;
+                self.position_frames += lost.round() as usize;
+            }
+            self.next_offset = Some(frame.offset + frame.len());
+            let position = self.position_frames * SAMPLE_COUNT;
+            self.position_frames += 1;
+
+            let buffer = &mut frame_buffer[..frame.data.len()];
+            buffer.copy_from_slice(frame.data);
+            let mut bits = SpeexBits::new();
+            bits.read_from(buffer);
+
+            match self.decoder.decode(&mut bits, &mut samples) {
+                Ok(()) => (),
+                Err(_) if self.salvage => {
+                    self.skipped.push(frame.offset..frame.offset + frame.len());
+                    continue;
+                }
+                Err(_) => {
+                    // nothing after a broken frame can be trusted
+                    self.frames = Vec::new().into_iter();
+                    return Some(Err(VopError::Decode(frame.offset)));
+                }
+            }
+
+            let decoded = DecodedFrame {
+                position,
+                submode: frame.submode(),
+                speech_detected: (frame.flags & 0x80) != 0,
+                samples: samples.map(|value| value / 32768.0),
+            };
+            self.decoded_frames += 1;
+
+            return Some(Ok(decoded));
+        }
+
+        None
     }
+}
+
+/// Decodes vop data into a wav file, returns what was salvaged
+pub fn decode(data: &[u8], output: &Path, salvage: bool, compensate_delay: bool) -> Result<SalvageReport, VopError> {
+    let mut decoder = VopDecoder::new(data, salvage)?;
+
+    let delay = if compensate_delay { CODEC_DELAY } else { 0 };
+    let mut error = None;
+    let samples = decoder
+        .by_ref()
+        .map_while(|frame| frame.map_err(|e| error = Some(e)).ok())
+        .flat_map(|frame| frame.samples)
+        .skip(delay);
+    write_wav(samples, output);
 
-    let mut decoder = SpeexDecoder::<NbMode>::new();
+    match error {
+        Some(e) => Err(e),
+        None => Ok(decoder.salvage_report()),
+    }
+}
 
+/// Writes 8 kHz samples to a 32-bit float wav file, or stdout
+pub fn write_wav(samples: impl Iterator<Item = f32>, output: &Path) {
     let spec = WavSpec {
         channels: 1,
         sample_rate: 8000,
         bits_per_sample: 32,
         sample_format: hound::SampleFormat::Float,
     };
-    let mut writer = WavWriter::create(output, spec).unwrap();
 
-    let mut frame_buffer = [0u8; (submode_bits_per_frame(NbSubmodeId::High) as usize + 7) >> 3];
-    let mut frame = [0f32; SAMPLE_COUNT];
-    while data.position() < size {
-        let flags = data.read_u8().unwrap();
-        //let speech_detected = (flags & 0x80) != 0;
-        //let unknown_bit = (flags & 0x40) != 0;
-        let op = flags & 0x3F;
-        let submode = op.clamp(1, 8);
-        let submode = NbSubmodeId::from(submode as i32);
+    // hound has to seek back to fill in the header, so stdout gets the whole thing at the end
+    match is_std_stream(output) {
+        true => {
+            let mut wav = Cursor::new(Vec::new());
+            write_samples(samples, WavWriter::new(&mut wav, spec).unwrap());
+            write_output(output, wav.get_ref());
+        }
+        false => write_samples(samples, WavWriter::create(output, spec).unwrap()),
+    }
+}
 
-        let bits_per_frame = submode_bits_per_frame(submode);
-        let bytes_per_frame = (bits_per_frame + 7) >> 3;
+fn write_samples<W: Write + Seek>(samples: impl Iterator<Item = f32>, mut writer: WavWriter<W>) {
+    for sample in samples {
+        writer.write_sample(sample).unwrap();
+    }
+
+    writer.finalize().unwrap();
+}
+
+/// Decodes vop data into samples in the -1.0 to 1.0 range, along with what was salvaged.
+///
+/// With `compensate_delay` enabled, the leading samples added by the codec delay are dropped.
+pub fn decode_samples(data: &[u8], salvage: bool, compensate_delay: bool) -> Result<(Vec<f32>, SalvageReport), VopError> {
+    let mut decoder = VopDecoder::new(data, salvage)?;
+
+    let delay = if compensate_delay { CODEC_DELAY } else { 0 };
+    let mut samples = Vec::new();
+    for frame in decoder.by_ref() {
+        samples.extend_from_slice(&frame?.samples);
+    }
+    samples.drain(..delay.min(samples.len()));
 
-        //println!("submode = {submode:?}, speech = {speech_detected}, unk. bit = {unknown_bit}");
+    Ok((samples, decoder.salvage_report()))
+}
 
-        let buffer = &mut frame_buffer[..bytes_per_frame as usize];
-        data.read_exact(buffer).unwrap();
-        let mut bits = SpeexBits::new();
-        bits.read_from(buffer);
+#[cfg(test)]
+mod tests {
+    use super::{decode_samples, read_frames, VopDecoder};
+    use crate::SAMPLE_COUNT;
+    use crate::encoding::{encode_data, EncoderSettings};
+    use crate::test_util::tone_then_silence;
 
-        decoder.decode(&mut bits, &mut frame).unwrap();
+    const CLICK_SPACING: usize = 1000;
 
-        for value in frame {
-            writer.write_sample(value / 32768.0).unwrap();
+    fn click_track() -> Vec<f32> {
+        let mut samples = vec![0.0; CLICK_SPACING * 8];
+        for click in (CLICK_SPACING..samples.len()).step_by(CLICK_SPACING) {
+            samples[click] = 0.6;
         }
+        samples
     }
 
-    writer.finalize().unwrap();
-}
\ No newline at end of file
+    fn click_positions(samples: &[f32]) -> Vec<usize> {
+        (CLICK_SPACING..samples.len())
+            .step_by(CLICK_SPACING)
+            .map(|click| {
+                let window = click - CLICK_SPACING / 4..(click + CLICK_SPACING / 4).min(samples.len());
+                window.max_by(|&a, &b| samples[a].abs().total_cmp(&samples[b].abs())).unwrap()
+            })
+            .collect()
+    }
+
+    fn round_trip(compensate_encode: bool, compensate_decode: bool) -> Vec<f32> {
+        let settings = EncoderSettings { compensate_delay: compensate_encode, ..Default::default() };
+        let data = encode_data(click_track(), &settings);
+        decode_samples(&data, false, compensate_decode).unwrap().0
+    }
+
+    // frame offsets of an undamaged encode, plus the end of the data
+    fn frame_offsets(data: &[u8]) -> Vec<usize> {
+        let (frames, _) = read_frames(data, false).unwrap();
+        frames.iter().map(|frame| frame.offset).chain([data.len()]).collect()
+    }
+
+    #[test]
+    fn uncompensated_round_trip_is_delayed() {
+        let expected = click_positions(&click_track());
+        assert_ne!(click_positions(&round_trip(false, false)), expected);
+    }
+
+    #[test]
+    fn encode_compensation_aligns_round_trip() {
+        let expected = click_positions(&click_track());
+        assert_eq!(click_positions(&round_trip(true, false)), expected);
+    }
+
+    #[test]
+    fn decode_compensation_aligns_round_trip() {
+        let expected = click_positions(&click_track());
+        assert_eq!(click_positions(&round_trip(false, true)), expected);
+    }
+
+    #[test]
+    fn salvage_skips_corrupted_frames() {
+        let mut data = encode_data(click_track(), &Default::default());
+        let offsets = frame_offsets(&data);
+        let frame_count = offsets.len() - 1;
+
+        // a flags byte with submode 0, and garbage over a whole frame a bit later
+        data[offsets[3]] = 0;
+        data[offsets[10]..offsets[11]].fill(0xFF);
+
+        let (frames, skipped) = read_frames(&data, true).unwrap();
+        assert_eq!(frames.len(), frame_count - 2);
+        assert_eq!(skipped, vec![offsets[3]..offsets[4], offsets[10]..offsets[11]]);
+        assert!(frames.iter().all(|frame| frame.offset != offsets[3] && frame.offset != offsets[10]));
+    }
+
+    #[test]
+    fn salvage_skips_truncated_frame() {
+        let data = encode_data(click_track(), &Default::default());
+        let offsets = frame_offsets(&data);
+        let frame_count = offsets.len() - 1;
+
+        // cut off in the middle of the last frame
+        let end = offsets[frame_count - 1] + 5;
+        let (frames, skipped) = read_frames(&data[..end], true).unwrap();
+        assert_eq!(frames.len(), frame_count - 1);
+        assert_eq!(skipped, vec![offsets[frame_count - 1]..end]);
+    }
+
+    #[test]
+    fn salvage_keeps_what_strict_decodes() {
+        let mut data = encode_data(tone_then_silence(), &EncoderSettings { vad: true, ..Default::default() });
+
+        // vocoder-like frames with a submode of 0 in the flags, the strict path clamps that back to 1
+        let offsets = frame_offsets(&data);
+        let mut changed = 0;
+        for &offset in &offsets[..offsets.len() - 1] {
+            if data[offset] & 0x3F == 1 {
+                data[offset] &= !0x3F;
+                changed += 1;
+            }
+        }
+        assert!(changed > 0);
+
+        let (strict, _) = read_frames(&data, false).unwrap();
+        let (salvaged, skipped) = read_frames(&data, true).unwrap();
+        assert_eq!(salvaged.len(), strict.len());
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn positions_count_skipped_frames() {
+        let mut data = encode_data(click_track(), &Default::default());
+        let offsets = frame_offsets(&data);
+        data[offsets[10]..offsets[12]].fill(0xFF);
+
+        let positions: Vec<usize> = VopDecoder::new(&data, true).unwrap().map(|frame| frame.unwrap().position).collect();
+        let expected: Vec<usize> = (0..offsets.len() - 1).filter(|i| !(10..12).contains(i)).map(|i| i * SAMPLE_COUNT).collect();
+        assert_eq!(positions, expected);
+
+        // and without salvaging it's an error instead of a panic
+        assert!(decode_samples(&data, false, false).is_err());
+    }
+}
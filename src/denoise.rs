@@ -0,0 +1,148 @@
+use std::{ops::Range, time::Duration};
+
+use realfft::RealFftPlanner;
+
+use crate::SPEEX_SAMPLE_RATE;
+
+const FFT_SIZE: usize = 256;
+const HOP: usize = FFT_SIZE / 2;
+
+// how much of the original spectrum is always kept, subtracting all the way down leaves "musical noise"
+const SPECTRAL_FLOOR: f32 = 0.05;
+
+// when looking for the quietest part automatically
+const PROFILE_LENGTH: Duration = Duration::from_millis(500);
+const PROFILE_STEP: usize = SPEEX_SAMPLE_RATE as usize / 10;
+
+#[derive(Clone, Copy)]
+pub struct DenoiseOptions {
+    /// How much of the noise profile gets subtracted, 1.0 is exactly the measured amount
+    pub strength: f32,
+    /// Where to learn the noise from, the quietest part of the input if not set
+    pub profile: Option<(Duration, Duration)>,
+}
+
+// sqrt hann, so analysis and synthesis windows together add up to 1 at 50% overlap
+fn window() -> Vec<f32> {
+    (0..FFT_SIZE)
+        .map(|i| (0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / FFT_SIZE as f32).cos()).sqrt())
+        .collect()
+}
+
+/// The quietest stretch of the input
+fn quietest_range(samples: &[f32]) -> Range<usize> {
+    let length = ((PROFILE_LENGTH.as_secs_f64() * SPEEX_SAMPLE_RATE as f64) as usize).min(samples.len());
+
+    let energy = |start: usize| samples[start..start + length].iter().map(|s| s * s).sum::<f32>();
+    let start = (0..=samples.len() - length)
+        .step_by(PROFILE_STEP)
+        .min_by(|a, b| energy(*a).total_cmp(&energy(*b)))
+        .unwrap_or(0);
+
+    start..start + length
+}
+
+/// Spectral subtraction: learns the average noise spectrum and takes it out of every frame
+pub fn denoise(samples: &mut [f32], options: &DenoiseOptions) {
+    let to_sample = |time: Duration| ((time.as_secs_f64() * SPEEX_SAMPLE_RATE as f64) as usize).min(samples.len());
+    let profile_range = match options.profile {
+        Some((start, end)) => to_sample(start)..to_sample(end),
+        None => quietest_range(samples),
+    };
+    eprintln!(
+        "learning noise from {:.2} s to {:.2} s",
+        profile_range.start as f64 / SPEEX_SAMPLE_RATE as f64,
+        profile_range.end as f64 / SPEEX_SAMPLE_RATE as f64,
+    );
+
+    let mut planner = RealFftPlanner::<f32>::new();
+    let forward = planner.plan_fft_forward(FFT_SIZE);
+    let inverse = planner.plan_fft_inverse(FFT_SIZE);
+    let window = window();
+    let mut frame = forward.make_input_vec();
+    let mut spectrum = forward.make_output_vec();
+
+    // pad so every sample is covered by two frames
+    let mut padded = vec![0.0; HOP];
+    padded.extend_from_slice(samples);
+    padded.resize(padded.len() + FFT_SIZE, 0.0);
+    let frame_starts = (0..padded.len() - FFT_SIZE + 1).step_by(HOP);
+
+    // average magnitude of the noise
+    let mut noise = vec![0.0f32; spectrum.len()];
+    let mut noise_frames = 0;
+    for start in frame_starts.clone() {
+        let original = start as isize - HOP as isize;
+        if original < profile_range.start as isize || original + FFT_SIZE as isize > profile_range.end as isize {
+            continue;
+        }
+        for i in 0..FFT_SIZE {
+            frame[i] = padded[start + i] * window[i];
+        }
+        forward.process(&mut frame, &mut spectrum).unwrap();
+        for (noise, bin) in noise.iter_mut().zip(&spectrum) {
+            *noise += bin.norm();
+        }
+        noise_frames += 1;
+    }
+    if noise_frames == 0 {
+        eprintln!("noise profile is too short, not denoising");
+        return;
+    }
+    noise.iter_mut().for_each(|noise| *noise *= options.strength / noise_frames as f32);
+
+    let mut output = vec![0.0f32; padded.len()];
+    for start in frame_starts {
+        for i in 0..FFT_SIZE {
+            frame[i] = padded[start + i] * window[i];
+        }
+        forward.process(&mut frame, &mut spectrum).unwrap();
+
+        for (bin, noise) in spectrum.iter_mut().zip(&noise) {
+            let magnitude = bin.norm();
+            if magnitude > 0.0 {
+                let cleaned = (magnitude - noise).max(magnitude * SPECTRAL_FLOOR);
+                *bin *= cleaned / magnitude;
+            }
+        }
+        // realfft wants these to be purely real
+        spectrum[0].im = 0.0;
+        spectrum[FFT_SIZE / 2].im = 0.0;
+
+        inverse.process(&mut spectrum, &mut frame).unwrap();
+        for i in 0..FFT_SIZE {
+            output[start + i] += frame[i] * window[i] / FFT_SIZE as f32;
+        }
+    }
+
+    samples.copy_from_slice(&output[HOP..HOP + samples.len()]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{denoise, DenoiseOptions};
+    use crate::test_util::{sine, tone_amplitude, white_noise};
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum()
+    }
+
+    #[test]
+    fn removes_noise_and_keeps_the_tone() {
+        // a second of noise on its own to learn from, then two with a tone on top
+        let noise = white_noise(0.05, 24000);
+        let mut tone = sine(1000.0, 0.3, 24000);
+        tone[..8000].fill(0.0);
+        let mut samples: Vec<f32> = tone.iter().zip(&noise).map(|(tone, noise)| tone + noise).collect();
+
+        denoise(&mut samples, &DenoiseOptions { strength: 1.0, profile: None });
+
+        // at least 6 dB less noise where there's only noise
+        assert!(energy(&samples[1000..7000]) < 0.25 * energy(&noise[1000..7000]));
+
+        // and where there's the tone, without taking the tone with it
+        assert!((tone_amplitude(&samples[12000..20000], 1000.0) - 0.3).abs() < 0.03);
+        let rest: Vec<f32> = samples.iter().zip(&tone).map(|(sample, tone)| sample - tone).collect();
+        assert!(energy(&rest[12000..20000]) < 0.25 * energy(&noise[12000..20000]));
+    }
+}
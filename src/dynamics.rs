@@ -0,0 +1,213 @@
+use std::time::Duration;
+
+use crate::SPEEX_SAMPLE_RATE;
+
+#[derive(Clone, Copy)]
+pub struct GateOptions {
+    /// The gate opens above this level, in dBFS
+    pub threshold: f64,
+    /// How long the gate takes to open
+    pub attack: Duration,
+    /// How long the gate takes to close
+    pub release: Duration,
+}
+
+pub(crate) fn db_to_amplitude(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+/// Per-sample smoothing coefficient for reaching ~63% of a change in `time`
+pub(crate) fn time_coefficient(time: Duration) -> f64 {
+    let samples = time.as_secs_f64() * SPEEX_SAMPLE_RATE as f64;
+    match samples > 0.0 {
+        true => (-1.0 / samples).exp(),
+        false => 0.0,
+    }
+}
+
+/// Silences everything below the threshold, fading in and out over the attack and release times
+pub fn noise_gate(samples: &mut [f32], options: &GateOptions) {
+    let threshold = db_to_amplitude(options.threshold);
+    let attack = time_coefficient(options.attack);
+    let release = time_coefficient(options.release);
+
+    // the envelope jumps up right away and falls off with the release, so the gate doesn't flutter
+    // between the peaks of a waveform
+    let mut envelope = 0.0;
+    let mut gain = 0.0;
+    let mut closed_samples = 0;
+    for sample in samples.iter_mut() {
+        let level = sample.abs() as f64;
+        envelope = match level > envelope {
+            true => level,
+            false => level + release * (envelope - level),
+        };
+
+        let target = match envelope > threshold {
+            true => 1.0,
+            false => 0.0,
+        };
+        let coefficient = if target > gain { attack } else { release };
+        gain = target + coefficient * (gain - target);
+
+        if gain < 0.5 {
+            closed_samples += 1;
+        }
+        *sample *= gain as f32;
+    }
+
+    eprintln!("noise gate was closed for {:.2} s", closed_samples as f64 / SPEEX_SAMPLE_RATE as f64);
+}
+
+#[derive(Clone, Copy)]
+pub struct CompressorOptions {
+    /// Level in dBFS where compression starts
+    pub threshold: f64,
+    /// How much the level over the threshold gets squashed, 4.0 means 4 dB in gives 1 dB out
+    pub ratio: f64,
+    pub attack: Duration,
+    pub release: Duration,
+    /// Gain in dB added afterwards, to make up for the lost level
+    pub makeup_gain: f64,
+}
+
+/// Evens out the level of speech by turning down everything over the threshold
+pub fn compress(samples: &mut [f32], options: &CompressorOptions) {
+    let attack = time_coefficient(options.attack);
+    let release = time_coefficient(options.release);
+    let makeup = db_to_amplitude(options.makeup_gain);
+
+    // gain reduction in dB, smoothed so it doesn't pump on every waveform peak
+    let mut reduction = 0.0;
+    for sample in samples.iter_mut() {
+        let level = 20.0 * (sample.abs() as f64).max(1e-9).log10();
+        let target = match level > options.threshold {
+            true => (level - options.threshold) * (1.0 - 1.0 / options.ratio),
+            false => 0.0,
+        };
+        let coefficient = if target > reduction { attack } else { release };
+        reduction = target + coefficient * (reduction - target);
+
+        *sample *= (db_to_amplitude(-reduction) * makeup) as f32;
+    }
+}
+
+/// Lookahead peak limiter, turns the gain down smoothly ahead of any peak over `ceiling` (dBFS)
+/// so nothing goes over it, then lets it back up over `release`
+pub fn limit(samples: &mut [f32], ceiling: f64, lookahead: Duration, release: Duration) {
+    let ceiling = db_to_amplitude(ceiling) as f32;
+    let lookahead = ((lookahead.as_secs_f64() * SPEEX_SAMPLE_RATE as f64) as usize).max(1);
+    let release = time_coefficient(release) as f32;
+
+    // gain each sample needs on its own
+    let required: Vec<f32> = samples.iter()
+        .map(|sample| match sample.abs() > ceiling {
+            true => ceiling / sample.abs(),
+            false => 1.0,
+        })
+        .collect();
+
+    // smallest gain needed anywhere within the lookahead, then averaged over the lookahead,
+    // which ramps the gain down ahead of a peak while still never going over what it needs
+    let window_min = |i: usize| {
+        let range = i.saturating_sub(lookahead)..(i + lookahead + 1).min(required.len());
+        required[range].iter().fold(1.0f32, |min, gain| min.min(*gain))
+    };
+    let minimums: Vec<f32> = (0..required.len()).map(window_min).collect();
+    let half = lookahead / 2;
+
+    let mut gain = 1.0f32;
+    let mut limited_samples = 0;
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let range = i.saturating_sub(half)..(i + half + 1).min(minimums.len());
+        let smoothed = minimums[range.clone()].iter().sum::<f32>() / range.len() as f32;
+
+        gain = match smoothed < gain {
+            true => smoothed,
+            false => smoothed + release * (gain - smoothed),
+        };
+        // the release never quite gets back to 1.0, so anything under 0.1 dB doesn't count
+        if gain < 0.99 {
+            limited_samples += 1;
+        }
+        *sample *= gain;
+    }
+
+    eprintln!("limiter was active for {:.2} s", limited_samples as f64 / SPEEX_SAMPLE_RATE as f64);
+}
+
+// soft clipping starts here, everything under it stays untouched
+const SOFT_CLIP_KNEE: f32 = 0.9;
+
+/// Rounds off peaks smoothly instead of hard clipping them, the output never goes over full scale
+pub fn soft_clip(samples: &mut [f32]) {
+    let headroom = 1.0 - SOFT_CLIP_KNEE;
+    for sample in samples.iter_mut() {
+        let level = sample.abs();
+        if level > SOFT_CLIP_KNEE {
+            let clipped = SOFT_CLIP_KNEE + headroom * ((level - SOFT_CLIP_KNEE) / headroom).tanh();
+            *sample = clipped.copysign(*sample);
+        }
+    }
+}
+
+/// Prints a warning about samples that went outside of the -1.0 to 1.0 range
+pub fn report_clipping(clipped_samples: usize) {
+    if clipped_samples > 0 {
+        eprintln!("warning: {clipped_samples} samples clipped, try --limiter, --soft-clip or turning the input down");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{db_to_amplitude, limit, noise_gate, soft_clip, GateOptions, SOFT_CLIP_KNEE};
+    use crate::test_util::{sine, white_noise};
+
+    #[test]
+    fn gate_mutes_below_the_threshold() {
+        // a second of -60 dB noise, then a second of a tone well above the threshold
+        let mut input = white_noise(0.001, 8000);
+        input.extend(sine(440.0, 0.5, 8000));
+
+        let mut samples = input.clone();
+        noise_gate(&mut samples, &GateOptions {
+            threshold: -40.0,
+            attack: Duration::from_millis(5),
+            release: Duration::from_millis(100),
+        });
+
+        assert!(samples[..8000].iter().all(|sample| sample.abs() < 1e-6));
+        // once it's had time to open, the tone goes through as it was
+        assert!(samples[8400..].iter().zip(&input[8400..]).all(|(sample, input)| (sample - input).abs() < 1e-3));
+    }
+
+    #[test]
+    fn limiter_stays_under_the_ceiling() {
+        // quiet, then a burst way over full scale, then quiet again
+        let mut samples = sine(440.0, 0.2, 24000);
+        samples[8000..16000].iter_mut().for_each(|sample| *sample *= 8.0);
+        // and a single spike, which gives the lookahead the least time
+        samples[20000] = 2.0;
+
+        limit(&mut samples, -1.0, Duration::from_millis(5), Duration::from_millis(50));
+
+        let ceiling = db_to_amplitude(-1.0) as f32;
+        assert!(samples.iter().all(|sample| sample.abs() <= ceiling + 1e-6));
+    }
+
+    #[test]
+    fn soft_clip_leaves_quiet_samples_alone() {
+        let input: Vec<f32> = (-100..=100).map(|i| i as f32 / 100.0 * SOFT_CLIP_KNEE).collect();
+        let mut samples = input.clone();
+        soft_clip(&mut samples);
+        assert!(samples == input);
+
+        let mut samples = vec![0.95, -1.0, 4.0];
+        soft_clip(&mut samples);
+        assert!(samples[0] > SOFT_CLIP_KNEE && samples[0] < 0.95);
+        assert!(samples[1] < -SOFT_CLIP_KNEE && samples[1] > -1.0);
+        assert!(samples[2] <= 1.0);
+    }
+}
@@ -1,46 +1,168 @@
 use std::{io::Write, path::Path};
 
 use byteorder::WriteBytesExt;
+use clap::ValueEnum;
 use crate::resource_parse::ResrcRevision;
 use crate::resource_write::write_resource;
 use speex_safe::{ControlFunctions, NbMode, NbSubmodeId, SpeexBits, SpeexEncoder};
 
-use crate::{submode_bits_per_frame, SAMPLE_COUNT};
+use crate::{submode_bits_per_frame, CODEC_DELAY, SAMPLE_COUNT};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum TailPadding {
+    /// Pad the last frame with silence
+    Zero,
+    /// Fade the last sample out to silence over the padding
+    Fade,
+}
+
+#[derive(Clone)]
+pub struct EncoderSettings {
+    pub quality: i32,
+    pub complexity: i32,
+    pub vad: bool,
+    pub highpass_filter: bool,
+    /// Start encoding ahead of the input to cancel out the codec delay,
+    /// so the decoded audio lines up with the input when played back as-is
+    pub compensate_delay: bool,
+    /// How to fill up the last frame if the input doesn't end on a frame boundary
+    pub tail_padding: TailPadding,
+    /// Number of silent frames to add after the input
+    pub trailing_silence: usize,
+}
+
+impl Default for EncoderSettings {
+    /// Same as the encode command's defaults
+    fn default() -> Self {
+        Self {
+            quality: 8,
+            complexity: 10,
+            vad: false,
+            highpass_filter: false,
+            compensate_delay: false,
+            tail_padding: TailPadding::Zero,
+            trailing_silence: 0,
+        }
+    }
+}
 
 pub fn encode(
     input_samples: Vec<f32>,
     output: &Path,
-    quality: i32,
-    complexity: i32,
-    vad: bool,
-    highpass_filter: bool,
+    settings: &EncoderSettings,
     revision: ResrcRevision
 ) {
-    let mut data = Vec::new();
-
-    let mut encoder = SpeexEncoder::<NbMode>::new();
-    // qualities over 8 crash the game lol
-    encoder.set_quality(quality);
-    encoder.set_complexity(complexity);
-    // submodes over high also crash the game lol
-    //encoder.set_submode(NbSubmodeId::VeryHigh);
-    encoder.set_vad(vad);
-    encoder.set_highpass(highpass_filter);
-
-    let mut frame_buffer = [0u8; (submode_bits_per_frame(NbSubmodeId::High) as usize + 7) >> 3];
-    let mut frame = [0f32; SAMPLE_COUNT];
-    
-    for chunk in input_samples.chunks(SAMPLE_COUNT) {
-        for (i, sample) in chunk.iter().enumerate() {
-            frame[i] = sample * 32768.0;
+    let data = encode_data(input_samples, settings);
+    write_resource(output, data, revision);
+}
+
+pub fn encode_data(input_samples: Vec<f32>, settings: &EncoderSettings) -> Vec<u8> {
+    let mut encoder = VopEncoder::new(settings);
+    encoder.push(&input_samples);
+    encoder.finish()
+}
+
+/// Encodes samples to vop data as they come in, so the whole input never has to be in memory
+pub struct VopEncoder {
+    encoder: SpeexEncoder<NbMode>,
+    settings: EncoderSettings,
+    /// Samples that don't make up a whole frame yet
+    pending: Vec<f32>,
+    /// How many samples are still left to skip for delay compensation
+    skip_left: usize,
+    frames: Vec<u8>,
+    /// How many samples were outside of the -1.0 to 1.0 range
+    clipped: usize,
+}
+
+impl VopEncoder {
+    pub fn new(settings: &EncoderSettings) -> Self {
+        let mut encoder = SpeexEncoder::<NbMode>::new();
+        // qualities over 8 crash the game lol
+        encoder.set_quality(settings.quality);
+        encoder.set_complexity(settings.complexity);
+        // submodes over high also crash the game lol
+        //encoder.set_submode(NbSubmodeId::VeryHigh);
+        encoder.set_vad(settings.vad);
+        encoder.set_highpass(settings.highpass_filter);
+
+        // skip the delayed part at the start, the end gets padded in finish() so the tail still makes it through
+        let skip_left = match settings.compensate_delay {
+            true => CODEC_DELAY,
+            false => 0,
+        };
+
+        Self {
+            encoder,
+            settings: settings.clone(),
+            pending: Vec::with_capacity(SAMPLE_COUNT),
+            skip_left,
+            frames: Vec::new(),
+            clipped: 0,
+        }
+    }
+
+    /// Adds 8 kHz samples in the -1.0 to 1.0 range
+    pub fn push(&mut self, mut samples: &[f32]) {
+        let skip = self.skip_left.min(samples.len());
+        samples = &samples[skip..];
+        self.skip_left -= skip;
+        // counted here rather than when encoding, so the last partial frame counts before finish()
+        self.clipped += samples.iter().filter(|sample| !(-1.0..=1.0).contains(*sample)).count();
+
+        while !samples.is_empty() {
+            let needed = SAMPLE_COUNT - self.pending.len();
+            let (taken, rest) = samples.split_at(needed.min(samples.len()));
+            self.pending.extend_from_slice(taken);
+            samples = rest;
+
+            if self.pending.len() == SAMPLE_COUNT {
+                self.encode_pending();
+            }
+        }
+    }
+
+    /// How many of the samples pushed so far had to be clamped
+    pub fn clipped_samples(&self) -> usize {
+        self.clipped
+    }
+
+    /// Pads out the last frame and returns the finished vop data
+    pub fn finish(mut self) -> Vec<u8> {
+        let skipped = match self.settings.compensate_delay {
+            true => CODEC_DELAY - self.skip_left,
+            false => 0,
+        };
+        self.skip_left = 0;
+        self.push(&vec![0.0; skipped]);
+
+        if !self.pending.is_empty() {
+            pad_tail(&mut self.pending, self.settings.tail_padding);
+            self.encode_pending();
+        }
+
+        for _ in 0..self.settings.trailing_silence {
+            self.push(&[0.0; SAMPLE_COUNT]);
+        }
+
+        vop_payload(self.frames)
+    }
+
+    fn encode_pending(&mut self) {
+        let mut frame_buffer = [0u8; (submode_bits_per_frame(NbSubmodeId::High) as usize + 7) >> 3];
+        let mut frame = [0f32; SAMPLE_COUNT];
+
+        for (i, sample) in self.pending.drain(..).enumerate() {
+            // speex expects its input to stay within 16 bits
+            frame[i] = (sample * 32768.0).clamp(-32768.0, 32767.0);
         }
 
         let mut bits = SpeexBits::new();
-        encoder.encode(&mut frame, &mut bits);
+        self.encoder.encode(&mut frame, &mut bits);
 
         let length = bits.write(&mut frame_buffer);
 
-        let submode = encoder.get_submode();
+        let submode = self.encoder.get_submode();
 
         let bits_per_frame = submode_bits_per_frame(submode);
         let bytes_per_frame = (bits_per_frame + 7) >> 3;
@@ -50,7 +172,7 @@ pub fn encode(
 
         let mut flags = (submode as i32) as u8;
 
-        if vad {
+        if self.settings.vad {
             if let NbSubmodeId::High = submode {
                 flags |= 0x80;
             }
@@ -62,13 +184,16 @@ pub fn encode(
             flags |= 0x80;
         }
 
-        data.write_u8(flags).unwrap();
-        data.write_all(&frame_buffer[..length as usize]).unwrap();
+        self.frames.write_u8(flags).unwrap();
+        self.frames.write_all(&frame_buffer[..length as usize]).unwrap();
     }
+}
 
+/// Prefixes encoded frames with their total size, which is what the game expects in a vop
+pub fn vop_payload(frames: Vec<u8>) -> Vec<u8> {
     let mut final_data = Vec::new();
 
-    let mut size = data.len();
+    let mut size = frames.len();
     loop {
         let mut b = size as u8 & 0x7F;
         size >>= 7;
@@ -82,7 +207,42 @@ pub fn encode(
         }
     };
 
-    final_data.write_all(&data).unwrap();
+    final_data.write_all(&frames).unwrap();
+
+    final_data
+}
+
+// fills up the last frame to a whole SAMPLE_COUNT samples
+fn pad_tail(samples: &mut Vec<f32>, padding: TailPadding) {
+    let padding_len = samples.len().next_multiple_of(SAMPLE_COUNT) - samples.len();
+    let last_sample = samples.last().copied().unwrap_or(0.0);
 
-    write_resource(output, final_data, revision);
-}
\ No newline at end of file
+    match padding {
+        TailPadding::Zero => samples.resize(samples.len() + padding_len, 0.0),
+        TailPadding::Fade => samples.extend((1..=padding_len).map(|i| {
+            last_sample * (1.0 - i as f32 / padding_len as f32)
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EncoderSettings, VopEncoder};
+    use crate::test_util::sine;
+
+    #[test]
+    fn counts_clipped_samples() {
+        let mut samples = sine(440.0, 1.2, 1000);
+        // right at full scale still fits
+        samples.extend([1.0, -1.0]);
+        let clipped = samples.iter().filter(|sample| sample.abs() > 1.0).count();
+        assert!(clipped > 0);
+
+        let mut encoder = VopEncoder::new(&EncoderSettings::default());
+        // split up so a frame gets filled across pushes
+        encoder.push(&samples[..100]);
+        encoder.push(&samples[100..]);
+        // the last 42 samples are still waiting for a whole frame
+        assert_eq!(encoder.clipped_samples(), clipped);
+    }
+}
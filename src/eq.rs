@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::dynamics::{db_to_amplitude, time_coefficient};
+use crate::filters::{filter_samples, high_pass, low_pass, peaking, Biquad};
+use crate::SPEEX_SAMPLE_RATE;
+
+const BUTTERWORTH_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+const PRESENCE_FREQUENCY: f64 = 2500.0;
+const PRESENCE_Q: f64 = 1.0;
+
+// everything over 4 kHz is gone at 8 kHz, so whatever sibilance is left sits right under it
+const SIBILANCE_FREQUENCY: f64 = 2800.0;
+const DE_ESSER_RATIO: f64 = 4.0;
+const DE_ESSER_ATTACK: Duration = Duration::from_millis(1);
+const DE_ESSER_RELEASE: Duration = Duration::from_millis(60);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum EqPreset {
+    /// Telephone band (300 to 3400 Hz), a bit of presence and a de-esser
+    Voice,
+    /// Wider band (80 to 3600 Hz) and nothing else
+    Music,
+    /// Cuts rumble under 150 Hz and keeps as much of the top as possible
+    Sfx,
+}
+
+/// Tone shaping before encoding, on top of speex's own highpass filter
+#[derive(Clone, Copy, Default)]
+pub struct EqOptions {
+    /// Band-pass edges in Hz, either one can be left out
+    pub low_cut: Option<f64>,
+    pub high_cut: Option<f64>,
+    /// Boost in dB around 2.5 kHz
+    pub presence: f64,
+    /// Sibilance over this many dBFS gets turned down
+    pub de_esser: Option<f64>,
+}
+
+impl EqPreset {
+    pub fn options(self) -> EqOptions {
+        match self {
+            Self::Voice => EqOptions { low_cut: Some(300.0), high_cut: Some(3400.0), presence: 3.0, de_esser: Some(-30.0) },
+            Self::Music => EqOptions { low_cut: Some(80.0), high_cut: Some(3600.0), presence: 0.0, de_esser: None },
+            Self::Sfx => EqOptions { low_cut: Some(150.0), high_cut: Some(3800.0), presence: 0.0, de_esser: None },
+        }
+    }
+}
+
+/// Runs the 8 kHz samples through the band-pass, presence boost and de-esser
+pub fn equalize(samples: &mut [f32], options: &EqOptions) {
+    let mut filters: Vec<Biquad> = Vec::new();
+    if let Some(freq) = options.low_cut {
+        filters.push(high_pass(SPEEX_SAMPLE_RATE, freq, BUTTERWORTH_Q));
+    }
+    if let Some(freq) = options.high_cut {
+        filters.push(low_pass(SPEEX_SAMPLE_RATE, freq, BUTTERWORTH_Q));
+    }
+    if options.presence != 0.0 {
+        filters.push(peaking(SPEEX_SAMPLE_RATE, PRESENCE_FREQUENCY, PRESENCE_Q, options.presence));
+    }
+    filter_samples(samples, &mut filters);
+
+    if let Some(threshold) = options.de_esser {
+        de_ess(samples, threshold);
+    }
+}
+
+/// Turns down the top of the band while it's louder than the threshold, the rest is left alone
+fn de_ess(samples: &mut [f32], threshold: f64) {
+    let threshold = db_to_amplitude(threshold);
+    let attack = time_coefficient(DE_ESSER_ATTACK);
+    let release = time_coefficient(DE_ESSER_RELEASE);
+
+    // linkwitz-riley crossover (two butterworths in a row on each side), the halves add back up flat
+    // so nothing changes while the de-esser isn't doing anything
+    let mut low = [0; 2].map(|_| low_pass(SPEEX_SAMPLE_RATE, SIBILANCE_FREQUENCY, BUTTERWORTH_Q));
+    let mut high = [0; 2].map(|_| high_pass(SPEEX_SAMPLE_RATE, SIBILANCE_FREQUENCY, BUTTERWORTH_Q));
+
+    let mut envelope = 0.0;
+    let mut active_samples = 0;
+    for sample in samples.iter_mut() {
+        let input = *sample as f64;
+        let low_band = low.iter_mut().fold(input, |value, filter| filter.process(value));
+        let high_band = high.iter_mut().fold(input, |value, filter| filter.process(value));
+
+        let level = high_band.abs();
+        let coefficient = if level > envelope { attack } else { release };
+        envelope = level + coefficient * (envelope - level);
+
+        let gain = match envelope > threshold {
+            true => {
+                active_samples += 1;
+                (threshold / envelope).powf(1.0 - 1.0 / DE_ESSER_RATIO)
+            }
+            false => 1.0,
+        };
+        *sample = (low_band + gain * high_band) as f32;
+    }
+
+    eprintln!("de-esser was active for {:.2} s", active_samples as f64 / SPEEX_SAMPLE_RATE as f64);
+}
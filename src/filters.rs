@@ -0,0 +1,102 @@
+use std::f64::consts::PI;
+
+/// Plain old direct form 1 biquad
+#[derive(Clone)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    /// Coefficients get normalized by `a[0]`
+    pub fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: b.map(|value| value / a[0]),
+            a: a.map(|value| value / a[0]),
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0] - self.a[2] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+/// Runs samples through a chain of filters in place
+pub fn filter_samples(samples: &mut [f32], filters: &mut [Biquad]) {
+    for sample in samples {
+        let mut value = *sample as f64;
+        for filter in filters.iter_mut() {
+            value = filter.process(value);
+        }
+        *sample = value as f32;
+    }
+}
+
+// the usual rbj cookbook filters, q of 0.7071 gives butterworth
+fn cookbook(sample_rate: u32, freq: f64, q: f64) -> (f64, f64) {
+    let w0 = 2.0 * PI * freq / sample_rate as f64;
+    (w0.cos(), w0.sin() / (2.0 * q))
+}
+
+pub fn high_pass(sample_rate: u32, freq: f64, q: f64) -> Biquad {
+    let (cos, alpha) = cookbook(sample_rate, freq, q);
+    Biquad::new(
+        [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+        [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+    )
+}
+
+pub fn low_pass(sample_rate: u32, freq: f64, q: f64) -> Biquad {
+    let (cos, alpha) = cookbook(sample_rate, freq, q);
+    Biquad::new(
+        [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+        [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+    )
+}
+
+/// Bell boost or cut of `gain` dB around `freq`
+pub fn peaking(sample_rate: u32, freq: f64, q: f64, gain: f64) -> Biquad {
+    let (cos, alpha) = cookbook(sample_rate, freq, q);
+    let a = 10f64.powf(gain / 40.0);
+    Biquad::new(
+        [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+        [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+    )
+}
+
+/// The two filters of the bs.1770 k-weighting curve, for any sample rate
+// formulas from libebur128, since the spec only has coefficients for 48 kHz
+pub fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    // high shelf
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let shelf = Biquad::new(
+        [vh + vb * k / q + k * k, 2.0 * (k * k - vh), vh - vb * k / q + k * k],
+        [1.0 + k / q + k * k, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+    );
+
+    // high pass
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [1.0 + k / q + k * k, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+    );
+
+    [shelf, high_pass]
+}
@@ -0,0 +1,233 @@
+use std::{f64::consts::PI, time::Duration};
+
+use clap::ValueEnum;
+
+use crate::dynamics::db_to_amplitude;
+use crate::SPEEX_SAMPLE_RATE;
+
+// dtmf keypad, rows are the low tone and columns the high one
+const DTMF_KEYS: [&str; 4] = ["123A", "456B", "789C", "*0#D"];
+const DTMF_LOW: [f64; 4] = [697.0, 770.0, 852.0, 941.0];
+const DTMF_HIGH: [f64; 4] = [1209.0, 1336.0, 1477.0, 1633.0];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Signal {
+    Sine,
+    /// Logarithmic sine sweep
+    Sweep,
+    /// Dtmf digits, each one a tone followed by a pause of the same length
+    Dtmf,
+    WhiteNoise,
+    PinkNoise,
+    Silence,
+    /// Single sample clicks at a fixed interval, starting right at the beginning
+    Clicks,
+}
+
+#[derive(Clone)]
+pub struct SignalOptions {
+    /// Peak level in dBFS
+    pub level: f64,
+    /// Sine frequency, or where the sweep starts
+    pub frequency: f64,
+    /// Where the sweep ends
+    pub end_frequency: f64,
+    pub digits: String,
+    pub click_interval: Duration,
+    /// Noise comes out the same for the same seed
+    pub seed: u64,
+}
+
+/// xorshift64*, good enough for noise and keeps things reproducible
+pub(crate) struct Random(u64);
+
+impl Random {
+    pub(crate) fn new(seed: u64) -> Self {
+        // zero would get stuck at zero
+        Self(seed.wrapping_mul(0x9E3779B97F4A7C15) | 1)
+    }
+
+    /// Uniform between -1.0 and 1.0
+    pub(crate) fn next(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let value = self.0.wrapping_mul(0x2545F4914F6CDD1D);
+        (value >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+}
+
+fn dtmf_tones(digit: char) -> Option<(f64, f64)> {
+    let digit = digit.to_ascii_uppercase();
+    DTMF_KEYS.iter().enumerate().find_map(|(row, keys)| {
+        keys.chars().position(|key| key == digit).map(|column| (DTMF_LOW[row], DTMF_HIGH[column]))
+    })
+}
+
+/// Generates `duration` worth of 8 kHz samples
+pub fn generate(signal: Signal, duration: Duration, options: &SignalOptions) -> Vec<f32> {
+    let rate = SPEEX_SAMPLE_RATE as f64;
+    let len = (duration.as_secs_f64() * rate).round() as usize;
+    let amplitude = db_to_amplitude(options.level);
+    let time = |i: usize| i as f64 / rate;
+
+    let samples: Vec<f64> = match signal {
+        Signal::Sine => (0..len).map(|i| (2.0 * PI * options.frequency * time(i)).sin()).collect(),
+        Signal::Sweep => {
+            // exponential sweep, the phase is the integral of f0 * (f1 / f0)^(t / T)
+            let (start, end) = (options.frequency, options.end_frequency);
+            let length = duration.as_secs_f64();
+            let growth = (end / start).ln();
+            (0..len).map(|i| {
+                let phase = match growth.abs() > f64::EPSILON {
+                    true => 2.0 * PI * start * length / growth * ((time(i) / length * growth).exp() - 1.0),
+                    false => 2.0 * PI * start * time(i),
+                };
+                phase.sin()
+            }).collect()
+        }
+        Signal::Dtmf => {
+            let digits: Vec<(f64, f64)> = options.digits.chars().filter_map(|digit| {
+                let tones = dtmf_tones(digit);
+                if tones.is_none() {
+                    eprintln!("warning: {digit} isn't a dtmf digit, skipping it");
+                }
+                tones
+            }).collect();
+
+            // at least a sample per digit, too short durations just come out silent
+            let slot = (len / digits.len().max(1)).max(1);
+            (0..len).map(|i| {
+                let index = i / slot;
+                match digits.get(index) {
+                    // tone for the first half of each slot, both tones at half level so the sum peaks at 1
+                    Some((low, high)) if i % slot < slot / 2 => {
+                        0.5 * (2.0 * PI * low * time(i)).sin() + 0.5 * (2.0 * PI * high * time(i)).sin()
+                    }
+                    _ => 0.0,
+                }
+            }).collect()
+        }
+        Signal::WhiteNoise => {
+            let mut random = Random::new(options.seed);
+            (0..len).map(|_| random.next()).collect()
+        }
+        Signal::PinkNoise => {
+            // paul kellett's refined pink noise filter
+            let mut random = Random::new(options.seed);
+            let mut b = [0.0; 7];
+            let samples: Vec<f64> = (0..len).map(|_| {
+                let white = random.next();
+                b[0] = 0.99886 * b[0] + white * 0.0555179;
+                b[1] = 0.99332 * b[1] + white * 0.0750759;
+                b[2] = 0.96900 * b[2] + white * 0.1538520;
+                b[3] = 0.86650 * b[3] + white * 0.3104856;
+                b[4] = 0.55000 * b[4] + white * 0.5329522;
+                b[5] = -0.7616 * b[5] - white * 0.0168980;
+                let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+                b[6] = white * 0.115926;
+                pink
+            }).collect();
+
+            // no fixed peak, so scale it to 1
+            let peak = samples.iter().fold(0.0f64, |peak, sample| peak.max(sample.abs()));
+            samples.iter().map(|sample| if peak > 0.0 { sample / peak } else { 0.0 }).collect()
+        }
+        Signal::Silence => vec![0.0; len],
+        Signal::Clicks => {
+            let interval = ((options.click_interval.as_secs_f64() * rate).round() as usize).max(1);
+            (0..len).map(|i| if i % interval == 0 { 1.0 } else { 0.0 }).collect()
+        }
+    };
+
+    samples.iter().map(|sample| (sample * amplitude) as f32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use realfft::RealFftPlanner;
+
+    use super::{generate, Signal, SignalOptions};
+    use crate::dynamics::db_to_amplitude;
+
+    fn options(digits: &str) -> SignalOptions {
+        SignalOptions {
+            level: -6.0,
+            frequency: 1000.0,
+            end_frequency: 3000.0,
+            digits: digits.to_string(),
+            click_interval: Duration::from_millis(100),
+            seed: 1,
+        }
+    }
+
+    #[test]
+    fn dtmf_shorter_than_the_digits() {
+        // 8 samples for 12 digits
+        let samples = generate(Signal::Dtmf, Duration::from_millis(1), &options("123456789*0#"));
+        assert_eq!(samples.len(), 8);
+        assert!(samples.iter().all(|sample| *sample == 0.0));
+
+        let samples = generate(Signal::Dtmf, Duration::from_millis(1), &options(""));
+        assert_eq!(samples.len(), 8);
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    /// Average frequency from the zero crossings, interpolated between samples
+    fn frequency(samples: &[f32]) -> f64 {
+        let crossings: Vec<f64> = samples.windows(2).enumerate()
+            .filter(|(_, pair)| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .map(|(i, pair)| i as f64 + (pair[0] / (pair[0] - pair[1])) as f64)
+            .collect();
+        let periods = (crossings.len() - 1) as f64 / 2.0;
+        periods / (crossings[crossings.len() - 1] - crossings[0]) * 8000.0
+    }
+
+    #[test]
+    fn sine_peaks_at_its_frequency() {
+        // a second at 8 kHz makes each bin 1 Hz
+        let samples = generate(Signal::Sine, Duration::from_secs(1), &options(""));
+        let mut planner = RealFftPlanner::<f32>::new();
+        let fft = planner.plan_fft_forward(samples.len());
+        let mut input = samples.clone();
+        let mut spectrum = fft.make_output_vec();
+        fft.process(&mut input, &mut spectrum).unwrap();
+
+        let bin = (0..spectrum.len()).max_by(|a, b| spectrum[*a].norm().total_cmp(&spectrum[*b].norm())).unwrap();
+        assert_eq!(bin, 1000);
+    }
+
+    #[test]
+    fn sweep_goes_from_start_to_end() {
+        let samples = generate(Signal::Sweep, Duration::from_secs(1), &options(""));
+        // 10 ms from each end, the sweep moves less than 1% over that
+        let start = frequency(&samples[..80]);
+        let end = frequency(&samples[samples.len() - 80..]);
+        assert!((start - 1000.0).abs() < 10.0, "starts at {start} Hz");
+        assert!((end - 3000.0).abs() < 30.0, "ends at {end} Hz");
+    }
+
+    #[test]
+    fn clicks_are_an_interval_apart() {
+        let samples = generate(Signal::Clicks, Duration::from_secs(1), &options(""));
+        let clicks: Vec<usize> = (0..samples.len()).filter(|i| samples[*i] != 0.0).collect();
+        assert_eq!(clicks, (0..8000).step_by(800).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn level_sets_the_peak() {
+        for level in [-6.0, -20.0] {
+            let options = SignalOptions { level, ..options("") };
+            let amplitude = db_to_amplitude(level) as f32;
+            for signal in [Signal::Sine, Signal::Sweep, Signal::WhiteNoise, Signal::PinkNoise, Signal::Clicks] {
+                let peak = peak(&generate(signal, Duration::from_secs(1), &options));
+                assert!(peak <= amplitude * 1.0001 && peak > amplitude * 0.99, "{signal:?} at {level} dB peaks at {peak}");
+            }
+        }
+    }
+}
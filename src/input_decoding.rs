@@ -1,63 +1,513 @@
-use std::{fs::File, path::Path};
+use std::{fmt::{self, Display, Formatter}, fs::File, io::{ErrorKind, Read}, path::Path, str::FromStr, time::Duration};
 
-use rubato::{Resampler, SincFixedIn, SincInterpolationType, SincInterpolationParameters, WindowFunction};
+use clap::ValueEnum;
+use rubato::{calculate_cutoff, FftFixedIn, SincFixedIn, SincInterpolationType, SincInterpolationParameters, VecResampler, WindowFunction};
 
-use symphonia::core::{audio::SampleBuffer, codecs::CODEC_TYPE_NULL};
-use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::{audio::{Channels, SampleBuffer}, codecs::{CODEC_TYPE_ADPCM_IMA_WAV, CODEC_TYPE_ADPCM_MS, CODEC_TYPE_NULL}};
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_PCM_F32LE, CODEC_TYPE_PCM_F64LE, CODEC_TYPE_PCM_S16BE,
+    CODEC_TYPE_PCM_S16LE, CODEC_TYPE_PCM_S24BE, CODEC_TYPE_PCM_S24LE, CODEC_TYPE_PCM_S32BE, CODEC_TYPE_PCM_S32LE};
 use symphonia::core::errors::Error;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
+use symphonia::core::formats::{FormatOptions, SeekMode, SeekTo, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream, ReadOnlySource};
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
+use symphonia::core::units::Time;
+
+use crate::{is_std_stream, SPEEX_SAMPLE_RATE};
+
+// if the stereo mix is this much quieter than the channels themselves, they're probably out of phase
+const CANCELLATION_RATIO: f64 = 0.1;
+
+/// Which part of the input gets encoded
+#[derive(Clone, Default)]
+pub struct InputOptions {
+    /// Track id, the first audio track if not set
+    pub track: Option<u32>,
+    /// Language of the audio track to use (e.g. eng), for containers with more than one
+    pub language: Option<String>,
+    pub channel: ChannelSelection,
+    /// Weight for each channel, overrides `channel`
+    pub downmix: Option<Vec<f32>>,
+    /// Where to start in the input
+    pub start: Option<Duration>,
+    /// Where to stop in the input
+    pub end: Option<Duration>,
+    pub resampler: ResamplerQuality,
+    /// Treat the input as headerless pcm instead of letting symphonia probe it
+    pub raw: Option<RawFormat>,
+}
+
+/// Layout of headerless pcm input
+#[derive(Clone, Copy)]
+pub struct RawFormat {
+    pub encoding: RawEncoding,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum RawEncoding {
+    /// Signed 16 bit little endian
+    S16le,
+    /// Signed 16 bit big endian
+    S16be,
+    /// 32 bit float little endian
+    F32le,
+    /// Unsigned 8 bit
+    U8,
+    /// G.711 mu-law
+    Mulaw,
+    /// G.711 a-law
+    Alaw,
+}
+
+impl RawEncoding {
+    fn sample_size(self) -> usize {
+        match self {
+            Self::S16le | Self::S16be => 2,
+            Self::F32le => 4,
+            Self::U8 | Self::Mulaw | Self::Alaw => 1,
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> f32 {
+        match self {
+            Self::S16le => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            Self::S16be => i16::from_be_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            Self::F32le => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            Self::U8 => (bytes[0] as f32 - 128.0) / 128.0,
+            Self::Mulaw => mulaw_to_linear(bytes[0]) as f32 / 32768.0,
+            Self::Alaw => alaw_to_linear(bytes[0]) as f32 / 32768.0,
+        }
+    }
+}
+
+// g.711 expansion, same as everyone else's
+fn mulaw_to_linear(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0F) as i16;
+    let magnitude = (((mantissa << 3) + 0x84) << exponent) - 0x84;
+    match byte & 0x80 {
+        0 => magnitude,
+        _ => -magnitude,
+    }
+}
+
+fn alaw_to_linear(byte: u8) -> i16 {
+    let byte = byte ^ 0x55;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0F) as i16;
+    let magnitude = match exponent {
+        0 => (mantissa << 4) + 8,
+        _ => ((mantissa << 4) + 0x108) << (exponent - 1),
+    };
+    match byte & 0x80 {
+        0 => -magnitude,
+        _ => magnitude,
+    }
+}
+
+/// How the input channels get turned into mono
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum ChannelSelection {
+    Left,
+    Right,
+    /// Channel index, starting at 0
+    Index(usize),
+    /// (left + right) / 2
+    Mid,
+    /// (left - right) / 2
+    Side,
+    /// Average of all channels, with center and surround channels turned down and lfe left out
+    #[default]
+    Mix,
+}
+
+impl FromStr for ChannelSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "left" => Ok(Self::Left),
+            "right" => Ok(Self::Right),
+            "mid" => Ok(Self::Mid),
+            "side" => Ok(Self::Side),
+            "mix" => Ok(Self::Mix),
+            _ => s.parse().map(Self::Index)
+                .map_err(|_| format!("expected left, right, mid, side, mix or a channel index, got {s}")),
+        }
+    }
+}
+
+/// Works out the weight of each channel for the downmix
+fn downmix_weights(options: &InputOptions, channels: Channels) -> Result<Vec<f32>, InputError> {
+    let count = channels.count();
+
+    if let Some(weights) = &options.downmix {
+        if weights.len() != count {
+            return Err(InputError::Channels(format!("downmix has {} weights, but the input has {count} channels", weights.len())));
+        }
+        return Ok(weights.clone());
+    }
+
+    let single = |index: usize| {
+        if index >= count {
+            return Err(InputError::Channels(format!("can't pick channel {index}, the input only has {count} channels")));
+        }
+        let mut weights = vec![0.0; count];
+        weights[index] = 1.0;
+        Ok(weights)
+    };
+    let stereo = |left: f32, right: f32| match count {
+        1 => vec![left + right],
+        _ => {
+            let mut weights = vec![0.0; count];
+            weights[0] = left;
+            weights[1] = right;
+            weights
+        }
+    };
+
+    Ok(match options.channel {
+        ChannelSelection::Left => single(0)?,
+        ChannelSelection::Right => single(1)?,
+        ChannelSelection::Index(index) => single(index)?,
+        ChannelSelection::Mid => stereo(0.5, 0.5),
+        ChannelSelection::Side => stereo(0.5, -0.5),
+        ChannelSelection::Mix => {
+            // usual itu downmix levels, normalized so it can't clip
+            let mut weights: Vec<f32> = channels.iter().map(|channel| {
+                if channel == Channels::LFE1 || channel == Channels::LFE2 {
+                    0.0
+                } else if channel == Channels::FRONT_LEFT || channel == Channels::FRONT_RIGHT {
+                    1.0
+                } else {
+                    std::f32::consts::FRAC_1_SQRT_2
+                }
+            }).collect();
+            let total: f32 = weights.iter().sum();
+            weights.iter_mut().for_each(|weight| *weight /= total);
+            weights
+        }
+    })
+}
+
+#[derive(Debug)]
+pub enum InputError {
+    /// The input couldn't be opened
+    Io(std::io::Error),
+    /// Symphonia doesn't know the format
+    Format(Error),
+    NoAudioTrack,
+    NoSuchTrack(u32),
+    /// No audio track in the requested language, along with the languages there are
+    NoSuchLanguage(String, Vec<String>),
+    /// Symphonia doesn't have a decoder for the codec
+    Codec(Error),
+    /// Reading from the input failed halfway through
+    Read(Error),
+    /// The channel selection doesn't work with the input
+    Channels(String),
+}
+
+impl Display for InputError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "can't open input: {e}"),
+            Self::Format(e) => write!(f, "unsupported format: {e}"),
+            Self::NoAudioTrack => write!(f, "no supported audio tracks"),
+            Self::NoSuchTrack(id) => write!(f, "no track with id {id}"),
+            Self::NoSuchLanguage(language, available) => match available.is_empty() {
+                true => write!(f, "no audio track in {language}, the tracks don't have languages"),
+                false => write!(f, "no audio track in {language}, there's {}", available.join(", ")),
+            },
+            Self::Codec(e) => write!(f, "unsupported codec: {e}"),
+            Self::Read(e) => write!(f, "can't read input: {e}"),
+            Self::Channels(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+/// Picks the requested track, or the first audio track (in the requested language) that can be decoded
+fn select_track<'a>(tracks: &'a [Track], id: Option<u32>, language: Option<&str>) -> Result<&'a Track, InputError> {
+    if let Some(id) = id {
+        return tracks.iter().find(|t| t.id == id).ok_or(InputError::NoSuchTrack(id));
+    }
+
+    // video and subtitle tracks come through as null
+    let audio: Vec<&Track> = tracks.iter().filter(|t| t.codec_params.codec != CODEC_TYPE_NULL).collect();
+    let candidates: Vec<&Track> = match language {
+        Some(language) => audio
+            .iter()
+            .filter(|t| t.language.as_deref().is_some_and(|l| l.eq_ignore_ascii_case(language)))
+            .copied()
+            .collect(),
+        None => audio.clone(),
+    };
+
+    // webm is usually opus, which symphonia can't decode, so skip over those if there's something else.
+    // if there isn't, the first one still gets picked so the error says what's wrong
+    let codecs = symphonia::default::get_codecs();
+    let decodable = candidates.iter().find(|t| codecs.get_codec(t.codec_params.codec).is_some());
+    match (decodable.or(candidates.first()), language) {
+        (Some(track), _) => Ok(track),
+        (None, Some(language)) => {
+            let mut available: Vec<String> = audio.iter().filter_map(|t| t.language.clone()).collect();
+            available.sort();
+            available.dedup();
+            Err(InputError::NoSuchLanguage(language.to_string(), available))
+        }
+        (None, None) => Err(InputError::NoAudioTrack),
+    }
+}
+
+fn make_decoder(track: &Track) -> Result<Box<dyn Decoder>, InputError> {
+    let mut params = track.codec_params.clone();
+
+    // symphonia's mkv reader doesn't fill this in, and the pcm decoder refuses to start without it.
+    // mkv blocks are usually a few ms, a whole second leaves plenty of room
+    let is_pcm = matches!(params.codec, CODEC_TYPE_PCM_S16LE | CODEC_TYPE_PCM_S16BE | CODEC_TYPE_PCM_S24LE
+        | CODEC_TYPE_PCM_S24BE | CODEC_TYPE_PCM_S32LE | CODEC_TYPE_PCM_S32BE | CODEC_TYPE_PCM_F32LE | CODEC_TYPE_PCM_F64LE);
+    if is_pcm && params.max_frames_per_packet.is_none() {
+        params.with_max_frames_per_packet(params.sample_rate.unwrap_or(48000) as u64);
+    }
+
+    symphonia::default::get_codecs()
+        .make(&params, &DecoderOptions::default())
+        .map_err(InputError::Codec)
+}
+
+/// Turns interleaved samples into 8 kHz mono, following along when the channels or the sample rate change
+struct MonoConverter<'a, F: FnMut(&[f32])> {
+    options: &'a InputOptions,
+    output: F,
+    channels: Option<Channels>,
+    weights: Vec<f32>,
+    sample_rate: u32,
+    resampler: Option<StreamResampler>,
+    /// Time of the next input sample in seconds
+    position: f64,
+    mono_samples: Vec<f32>,
+    // for spotting stereo channels that cancel each other out
+    check_cancellation: bool,
+    channel_energy: f64,
+    mix_energy: f64,
+}
+
+impl<'a, F: FnMut(&[f32])> MonoConverter<'a, F> {
+    fn new(options: &'a InputOptions, output: F, position: f64) -> Self {
+        Self {
+            options,
+            output,
+            channels: None,
+            weights: Vec::new(),
+            sample_rate: SPEEX_SAMPLE_RATE,
+            resampler: None,
+            position,
+            mono_samples: Vec::new(),
+            check_cancellation: options.downmix.is_none()
+                && matches!(options.channel, ChannelSelection::Mix | ChannelSelection::Mid),
+            channel_energy: 0.0,
+            mix_energy: 0.0,
+        }
+    }
+
+    /// Whether everything up to the end of the range is in
+    fn is_done(&self) -> bool {
+        self.options.end.is_some_and(|end| self.position >= end.as_secs_f64())
+    }
+
+    fn flush_resampler(&mut self) {
+        if let Some(resampler) = self.resampler.take() {
+            resampler.finish(&mut self.output);
+        }
+    }
+
+    /// Takes interleaved samples
+    fn push(&mut self, samples: &[f32], channels: Channels, sample_rate: u32) -> Result<(), InputError> {
+        if self.channels != Some(channels) {
+            self.weights = downmix_weights(self.options, channels)?;
+            self.channels = Some(channels);
+        }
+
+        // a new resampler for the new rate, the old one has to get its tail out first
+        if sample_rate != self.sample_rate || (self.resampler.is_none() && sample_rate != SPEEX_SAMPLE_RATE) {
+            self.flush_resampler();
+            self.sample_rate = sample_rate;
+            if sample_rate != SPEEX_SAMPLE_RATE {
+                self.resampler = Some(StreamResampler::new(sample_rate, self.options.resampler));
+            }
+        }
+
+        // multiple channel to mono conversion
+        self.mono_samples.clear();
+        for samples in samples.chunks(self.weights.len()) {
+            let mut mixed = 0.0;
+            for (sample, weight) in samples.iter().zip(&self.weights) {
+                mixed += sample * weight;
+            }
+            self.mono_samples.push(mixed);
+
+            if self.check_cancellation && samples.len() == 2 {
+                self.channel_energy += (samples[0] * samples[0] + samples[1] * samples[1]) as f64 / 2.0;
+                self.mix_energy += (mixed * mixed) as f64;
+            }
+        }
+
+        // cut out whatever is outside of the range
+        let rate = sample_rate as f64;
+        let len = self.mono_samples.len();
+        let buffer_start = self.position;
+        self.position += len as f64 / rate;
+        let to_index = |time: Duration| ((time.as_secs_f64() - buffer_start) * rate).round().clamp(0.0, len as f64) as usize;
+        let from = self.options.start.map_or(0, to_index);
+        let to = self.options.end.map_or(len, to_index);
+        let samples = &self.mono_samples[from..to.max(from)];
+
+        match &mut self.resampler {
+            Some(resampler) => resampler.process(samples, &mut self.output),
+            None => (self.output)(samples),
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) {
+        self.flush_resampler();
+
+        if self.channel_energy > 0.0 && self.mix_energy < self.channel_energy * CANCELLATION_RATIO {
+            eprintln!("warning: the left and right channels mostly cancel each other out, try --channel left or --channel side");
+        }
+    }
+}
+
+/// Same as [`decode_input`] but for headerless pcm, there's nothing to probe so symphonia stays out of it
+fn decode_raw(mut reader: impl Read, format: &RawFormat, options: &InputOptions, output: impl FnMut(&[f32])) -> Result<(), InputError> {
+    // the speaker order is the usual wav one
+    let channels = Channels::from_bits_truncate((1u32 << format.channels) - 1);
+    if channels.count() != format.channels as usize {
+        return Err(InputError::Channels(format!("raw input can have at most {} channels", channels.count())));
+    }
+
+    let frame_size = format.encoding.sample_size() * format.channels as usize;
+    let mut bytes = vec![0u8; frame_size * 4096];
+    let mut samples = Vec::with_capacity(4096 * format.channels as usize);
+    let mut filled = 0;
+
+    let mut converter = MonoConverter::new(options, output, 0.0);
+
+    while !converter.is_done() {
+        let read = match reader.read(&mut bytes[filled..]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(InputError::Read(Error::IoError(e))),
+        };
+        filled += read;
+
+        // only whole frames, the rest waits for the next read
+        let whole = filled - filled % frame_size;
+        samples.clear();
+        samples.extend(bytes[..whole].chunks(format.encoding.sample_size()).map(|sample| format.encoding.decode(sample)));
+        converter.push(&samples, channels, format.sample_rate)?;
+
+        bytes.copy_within(whole..filled, 0);
+        filled -= whole;
+    }
+
+    converter.finish();
+
+    if filled > 0 {
+        eprintln!("warning: the input ends in the middle of a frame, dropped the last {filled} bytes");
+    }
 
-const SPEEX_SAMPLE_RATE: u32 = 8000;
+    Ok(())
+}
 
+/// Decodes an audio file, and passes it to `output` as 8 kHz mono samples while it's being decoded
 // code mostly based on https://github.com/pdeljanov/Symphonia/blob/master/symphonia/examples/basic-interleaved.rs
-pub fn decode_input(path: &Path) -> Vec<f32> {
-    let file = Box::new(File::open(path).unwrap());
+pub fn decode_input(path: &Path, options: &InputOptions, output: impl FnMut(&[f32])) -> Result<(), InputError> {
+    if let Some(format) = &options.raw {
+        return match is_std_stream(path) {
+            true => decode_raw(std::io::stdin().lock(), format, options, output),
+            false => decode_raw(File::open(path).map_err(InputError::Io)?, format, options, output),
+        };
+    }
 
-    let mss = MediaSourceStream::new(file, Default::default());
+    // stdin can't seek, symphonia copes with that as long as it knows
+    let source: Box<dyn MediaSource> = match is_std_stream(path) {
+        true => Box::new(ReadOnlySource::new(std::io::stdin())),
+        false => Box::new(File::open(path).map_err(InputError::Io)?),
+    };
 
-    let hint = Hint::new();
+    let mss = MediaSourceStream::new(source, Default::default());
+
+    // the extension lets symphonia try the right format first instead of probing all of them
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
 
     let format_opts: FormatOptions = Default::default();
     let metadata_opts: MetadataOptions = Default::default();
-    let decoder_opts: DecoderOptions = Default::default();
 
     let probed = symphonia::default::get_probe()
         .format(&hint, mss, &format_opts, &metadata_opts)
-        .expect("unsupported format");
+        .map_err(InputError::Format)?;
 
     let mut format = probed.format;
 
-    let track = format
-        .tracks()
-        .iter()
-        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-        .expect("no supported audio tracks");
-
-    let sample_rate = track.codec_params.sample_rate.unwrap();
-    let num_channels = match track.codec_params.channels {
-        Some(channels) => channels.count(),
-        None => 1,
-    };
-
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &decoder_opts)
-        .expect("unsupported codec");
+    let track = select_track(format.tracks(), options.track, options.language.as_deref())?;
+    let mut decoder = make_decoder(track)?;
 
     // Store the track identifier, we'll use it to filter packets.
-    let track_id = track.id;
+    let mut track_id = track.id;
+    let time_base = track.codec_params.time_base;
+    let sample_rate = track.codec_params.sample_rate;
+    // symphonia's wav reader works out adpcm seek offsets in frames instead of blocks and ends up past the end
+    let can_seek = !matches!(track.codec_params.codec, CODEC_TYPE_ADPCM_IMA_WAV | CODEC_TYPE_ADPCM_MS);
+
+    // time of the next decoded sample in seconds
+    let mut position = 0.0;
+
+    // seek close to the start if the format can, otherwise everything before it just gets decoded and thrown away.
+    // without a time base or a sample rate there's no telling where the seek ended up, so don't bother
+    let can_seek = can_seek && (time_base.is_some() || sample_rate.is_some());
+    if let Some(start) = options.start.filter(|start| !start.is_zero() && can_seek) {
+        let seek_to = SeekTo::Time { time: Time::from(start.as_secs_f64()), track_id: Some(track_id) };
+        if let Ok(seeked) = format.seek(SeekMode::Accurate, seek_to) {
+            position = match (time_base, sample_rate) {
+                (Some(time_base), _) => {
+                    let time = time_base.calc_time(seeked.actual_ts);
+                    time.seconds as f64 + time.frac
+                }
+                (None, Some(rate)) => seeked.actual_ts as f64 / rate as f64,
+                (None, None) => unreachable!(),
+            };
+            decoder.reset();
+        }
+    }
 
-    let mut sample_buf = None;
-    let mut final_samples = Vec::new();
+    let mut converter = MonoConverter::new(options, output, position);
+    let mut sample_buf: Option<SampleBuffer<f32>> = None;
+    let mut damaged_packets = 0;
 
-    loop {
+    while !converter.is_done() {
         // Get the next packet from the format reader.
-        let packet = format.next_packet();
-        let packet = match packet {
-            Ok(p) => p,
-            Err(_) => break
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            // the tracks changed (e.g. chained ogg streams), start over with a new decoder
+            Err(Error::ResetRequired) => {
+                let track = select_track(format.tracks(), options.track, options.language.as_deref())?;
+                track_id = track.id;
+                decoder = make_decoder(track)?;
+                continue;
+            }
+            Err(e) => return Err(InputError::Read(e)),
         };
 
         // If the packet does not belong to the selected track, skip it.
@@ -65,95 +515,397 @@ pub fn decode_input(path: &Path) -> Vec<f32> {
             continue;
         }
 
-        match decoder.decode(&packet) {
-            Ok(audio_buf) => {
-                if sample_buf.is_none() {
-                    let spec = *audio_buf.spec();
+        let audio_buf = match decoder.decode(&packet) {
+            Ok(audio_buf) => audio_buf,
+            Err(Error::DecodeError(_)) => {
+                damaged_packets += 1;
+                continue;
+            }
+            Err(Error::ResetRequired) => {
+                let track = select_track(format.tracks(), Some(track_id), None)?;
+                decoder = make_decoder(track)?;
+                continue;
+            }
+            Err(e) => return Err(InputError::Read(e)),
+        };
 
-                    let duration = audio_buf.capacity() as u64;
+        // the spec can change halfway through and packets don't all have to be the same size
+        let spec = *audio_buf.spec();
+        let needed = audio_buf.capacity() * spec.channels.count();
+        if sample_buf.as_ref().is_none_or(|buf| buf.capacity() < needed) {
+            sample_buf = Some(SampleBuffer::<f32>::new(audio_buf.capacity() as u64, spec));
+        }
 
-                    sample_buf = Some(SampleBuffer::<f32>::new(duration, spec));
-                }
+        let buf = sample_buf.as_mut().unwrap();
+        buf.copy_interleaved_ref(audio_buf);
+        converter.push(buf.samples(), spec.channels, spec.rate)?;
+    }
 
-                if let Some(buf) = &mut sample_buf {
-                    buf.copy_interleaved_ref(audio_buf);
-
-                    // multiple channel to mono conversion
-                    for samples in buf.samples().chunks(num_channels) {
-                        let mut average = 0.0;
-                        for sample in samples {
-                            average += sample;
-                        }
-                        average /= num_channels as f32;
-                        final_samples.push(average);
-                    }
-                }
-            }
-            Err(Error::DecodeError(_)) => (),
-            Err(_) => break,
+    converter.finish();
+
+    if damaged_packets > 0 {
+        eprintln!("warning: skipped {damaged_packets} damaged packets");
+    }
+
+    Ok(())
+}
+
+pub fn resample(indata: Vec<f32>, sample_rate: u32, quality: ResamplerQuality) -> Vec<f32> {
+    let mut outdata = Vec::with_capacity(
+        (indata.len() as f32 * SPEEX_SAMPLE_RATE as f32 / sample_rate as f32) as usize
+    );
+
+    let mut resampler = StreamResampler::new(sample_rate, quality);
+    resampler.process(&indata, &mut |samples| outdata.extend_from_slice(samples));
+    resampler.finish(&mut |samples| outdata.extend_from_slice(samples));
+
+    outdata
+}
+
+/// Trade-off between resampling speed and quality
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, ValueEnum)]
+pub enum ResamplerQuality {
+    /// Fft based, quickest but with a bit more aliasing
+    Fast,
+    /// Short sinc with linear interpolation between the sinc points
+    #[default]
+    Balanced,
+    /// Long sinc with cubic interpolation between the sinc points, slowest
+    Best,
+}
+
+/// Resamples to 8 kHz in chunks, trimming off the resampler delay
+pub struct StreamResampler {
+    resampler: Box<dyn VecResampler<f32>>,
+    ratio: f64,
+    /// Input that doesn't make up a whole chunk yet
+    indata: [Vec<f32>; 1],
+    outbuffer: [Vec<f32>; 1],
+    /// How many output samples are still left to drop because of the resampler delay
+    delay_left: usize,
+    input_frames: usize,
+    output_frames: usize,
+    /// How many output samples there should be in total, only known once all the input is in
+    output_limit: usize,
+}
+
+impl StreamResampler {
+    pub fn new(sample_rate: u32, quality: ResamplerQuality) -> Self {
+        // time for some stupid ass resampling code which i barely even understand :)))
+        // based on https://github.com/HEnquist/rubato/blob/master/examples/process_f64.rs
+
+        let ratio = SPEEX_SAMPLE_RATE as f64 / sample_rate as f64;
+
+        // the cutoff goes as close to nyquist as the window lets it without aliasing
+        let sinc = |sinc_len, oversampling_factor, window, interpolation| {
+            let params = SincInterpolationParameters {
+                sinc_len,
+                f_cutoff: calculate_cutoff(sinc_len, window),
+                interpolation,
+                oversampling_factor,
+                window,
+            };
+            Box::new(SincFixedIn::<f32>::new(ratio, 2.0, params, 1024, 1).unwrap())
+        };
+
+        let resampler: Box<dyn VecResampler<f32>> = match quality {
+            ResamplerQuality::Fast => Box::new(
+                FftFixedIn::<f32>::new(sample_rate as usize, SPEEX_SAMPLE_RATE as usize, 1024, 2, 1).unwrap()
+            ),
+            ResamplerQuality::Balanced => sinc(128, 128, WindowFunction::Blackman2, SincInterpolationType::Linear),
+            ResamplerQuality::Best => sinc(512, 256, WindowFunction::BlackmanHarris2, SincInterpolationType::Cubic),
+        };
+
+        // rubato's sinc resamplers already start half a sinc early, so their output isn't actually delayed
+        // even though output_delay() says it is (checked with clicks, trimming it shifted them early)
+        let delay_left = match quality {
+            ResamplerQuality::Fast => resampler.output_delay(),
+            ResamplerQuality::Balanced | ResamplerQuality::Best => 0,
+        };
+        let outbuffer = [vec![0.0f32; resampler.output_frames_max()]];
+
+        Self {
+            resampler,
+            ratio,
+            indata: [Vec::new()],
+            outbuffer,
+            delay_left,
+            input_frames: 0,
+            output_frames: 0,
+            output_limit: usize::MAX,
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32], output: &mut impl FnMut(&[f32])) {
+        self.input_frames += samples.len();
+        self.indata[0].extend_from_slice(samples);
+
+        while self.indata[0].len() >= self.resampler.input_frames_next() {
+            let (nbr_in, nbr_out) = self.resampler
+                .process_into_buffer(&self.indata, &mut self.outbuffer, None)
+                .unwrap();
+            self.indata[0].drain(..nbr_in);
+            self.output(nbr_out, output);
         }
     }
 
-    match sample_rate == SPEEX_SAMPLE_RATE {
-        true => final_samples,
-        false => resample(final_samples, sample_rate)
+    /// Flushes out the rest of the input
+    pub fn finish(mut self, output: &mut impl FnMut(&[f32])) {
+        self.output_limit = (self.input_frames as f64 * self.ratio) as usize;
+
+        // Process a partial chunk with the last frames.
+        if !self.indata[0].is_empty() {
+            let (_nbr_in, nbr_out) = self.resampler
+                .process_partial_into_buffer(Some(&self.indata), &mut self.outbuffer, None)
+                .unwrap();
+            self.output(nbr_out, output);
+        }
+
+        // keep feeding silence until the delayed part is out too
+        while self.output_frames < self.output_limit {
+            let (_nbr_in, nbr_out) = self.resampler
+                .process_partial_into_buffer(None, &mut self.outbuffer, None)
+                .unwrap();
+            self.output(nbr_out, output);
+        }
+    }
+
+    fn output(&mut self, nbr_out: usize, output: &mut impl FnMut(&[f32])) {
+        let skip = self.delay_left.min(nbr_out);
+        self.delay_left -= skip;
+
+        let remaining = self.output_limit.saturating_sub(self.output_frames);
+        let end = nbr_out.min(skip.saturating_add(remaining));
+        let samples = &self.outbuffer[0][skip..end];
+        self.output_frames += samples.len();
+        output(samples);
     }
 }
 
-fn resample(indata: Vec<f32>, sample_rate: u32) -> Vec<f32> {
-    // time for some stupid ass resampling code which i barely even understand :)))
-    // based on https://github.com/HEnquist/rubato/blob/master/examples/process_f64.rs
+#[cfg(test)]
+mod tests {
+    use std::{f32::consts::PI, io::{ErrorKind, Read}, path::Path, time::{Duration, Instant}};
 
-    let params = SincInterpolationParameters {
-        sinc_len: 256,
-        f_cutoff: 0.95,
-        interpolation: SincInterpolationType::Linear,
-        oversampling_factor: 256,
-        window: WindowFunction::BlackmanHarris2,
-    };
+    use symphonia::core::audio::Channels;
+    use symphonia::core::codecs::{CodecParameters, CODEC_TYPE_NULL, CODEC_TYPE_OPUS};
+    use symphonia::core::formats::Track;
 
-    let rate_in = sample_rate;
+    use super::{alaw_to_linear, decode_raw, make_decoder, mulaw_to_linear, decode_input, select_track, InputError, InputOptions,
+        MonoConverter, RawEncoding, RawFormat, ResamplerQuality, StreamResampler};
+    use crate::test_util::{sine, temp_path, tone_amplitude};
 
-    let mut resampler = SincFixedIn::<f32>::new(
-        SPEEX_SAMPLE_RATE as f64 / rate_in as f64,
-        2.0,
-        params,
-        1024,
-        1,
-    ).unwrap();
+    // the fixtures are 0.25 s of a half scale 440 Hz sine at 16 kHz
+    fn decode_fixture(name: &str) -> Vec<f32> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+        let mut samples = Vec::new();
+        decode_input(&path, &Default::default(), |chunk| samples.extend_from_slice(chunk)).unwrap();
+        samples
+    }
 
-    let mut indata = &indata[..];
-    let nbr_input_frames = indata.len();
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
 
-    let mut outdata = Vec::with_capacity(
-        2 * (nbr_input_frames as f32 * SPEEX_SAMPLE_RATE as f32 / rate_in as f32) as usize
-    );
+    // adpcm gets padded to whole blocks and vorbis loses a bit at the edges, so the length doesn't have to be exact
+    fn assert_length(samples: &[f32]) {
+        assert!(samples.len().abs_diff(2000) < 50, "got {} samples", samples.len());
+    }
+
+    fn assert_sine(samples: &[f32]) {
+        assert_length(samples);
+        // skip the edges, the resampler smears those
+        let rms = rms(&samples[200..1800]);
+        assert!((rms - 0.5 / 2f32.sqrt()).abs() < 0.01, "rms is {rms}");
+    }
 
-    let mut input_frames_next = resampler.input_frames_next();
-    let resampler_delay = resampler.output_delay();
-    let mut outbuffer = [vec![0.0f32; resampler.output_frames_max()]];
+    #[test]
+    fn wav() {
+        assert_sine(&decode_fixture("sine.wav"));
+    }
 
-    while indata.len() >= input_frames_next {
-        let (nbr_in, nbr_out) = resampler
-            .process_into_buffer(&[&indata], &mut outbuffer, None)
-            .unwrap();
-        indata = &indata[nbr_in..];
-        outdata.extend(&outbuffer[0][..nbr_out]);
-        input_frames_next = resampler.input_frames_next();
+    #[test]
+    fn aiff() {
+        assert_sine(&decode_fixture("sine.aiff"));
     }
 
-    // Process a partial chunk with the last frames.
-    if !indata.is_empty() {
-        let (_nbr_in, nbr_out) = resampler
-            .process_partial_into_buffer(Some(&[&indata]), &mut outbuffer, None)
-            .unwrap();
+    #[test]
+    fn flac() {
+        assert_sine(&decode_fixture("sine.flac"));
+    }
 
-        outdata.extend(&outbuffer[0][..nbr_out]);
+    #[test]
+    fn ima_adpcm() {
+        assert_sine(&decode_fixture("sine_ima_adpcm.wav"));
     }
 
-    let nbr_output_frames = (nbr_input_frames as f32 * SPEEX_SAMPLE_RATE as f32 / rate_in as f32) as usize;
-    let end = (resampler_delay + nbr_output_frames).min(outdata.len() - 1);
+    #[test]
+    fn ms_adpcm() {
+        assert_sine(&decode_fixture("sine_ms_adpcm.wav"));
+    }
 
-    let outdata = &outdata[resampler_delay..end];
-    outdata.to_vec()
-}
\ No newline at end of file
+    #[test]
+    fn vorbis() {
+        assert_sine(&decode_fixture("sine.ogg"));
+    }
+
+    #[test]
+    fn mkv() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sine.mkv");
+        let decode = |options: &InputOptions| {
+            let mut samples = Vec::new();
+            decode_input(&path, options, |chunk| samples.extend_from_slice(chunk)).map(|_| samples)
+        };
+
+        // the video track gets skipped, the first audio track is the silent english one
+        let samples = decode(&Default::default()).unwrap();
+        assert_length(&samples);
+        assert!(samples.iter().all(|sample| *sample == 0.0));
+
+        assert_sine(&decode(&InputOptions { language: Some("jpn".into()), ..Default::default() }).unwrap());
+        assert_sine(&decode(&InputOptions { track: Some(3), ..Default::default() }).unwrap());
+        assert!(matches!(
+            decode(&InputOptions { language: Some("fre".into()), ..Default::default() }),
+            Err(InputError::NoSuchLanguage(_, available)) if available == ["eng", "jpn"]
+        ));
+    }
+
+    #[test]
+    fn raw() {
+        let sine = (0..4000).map(|i| (0.5 * (2.0 * std::f64::consts::PI * 440.0 * i as f64 / 16000.0).sin() * 32767.0) as i16);
+        // stereo with the sine in the left channel only, the mix is half of it
+        let bytes: Vec<u8> = sine.flat_map(|sample| [sample.to_le_bytes(), [0, 0]].concat()).collect();
+        let format = RawFormat { encoding: RawEncoding::S16le, sample_rate: 16000, channels: 2 };
+        let options = InputOptions { channel: super::ChannelSelection::Left, ..Default::default() };
+
+        let mut samples = Vec::new();
+        decode_raw(&bytes[..], &format, &options, |chunk| samples.extend_from_slice(chunk)).unwrap();
+        assert_sine(&samples);
+    }
+
+    #[test]
+    fn follows_rate_and_channel_changes() {
+        // symphonia's decoders keep the same spec all the way through, so this goes straight to the converter:
+        // 0.25 s of mono at 16 kHz, then 0.5 s of stereo at 8 kHz
+        let mono: Vec<f32> = (0..4000).map(|i| 0.5 * (2.0 * PI * 440.0 * i as f32 / 16000.0).sin()).collect();
+        let stereo: Vec<f32> = sine(440.0, 0.5, 4000).iter().flat_map(|sample| [*sample, *sample]).collect();
+
+        let options = InputOptions::default();
+        let mut samples = Vec::new();
+        let mut converter = MonoConverter::new(&options, |chunk: &[f32]| samples.extend_from_slice(chunk), 0.0);
+        converter.push(&mono, Channels::FRONT_LEFT, 16000).unwrap();
+        converter.push(&stereo, Channels::FRONT_LEFT | Channels::FRONT_RIGHT, 8000).unwrap();
+        converter.finish();
+
+        assert_eq!(samples.len(), 6000);
+        for part in [&samples[200..1800], &samples[2200..5800]] {
+            let rms = rms(part);
+            assert!((rms - 0.5 / 2f32.sqrt()).abs() < 0.01, "rms is {rms}");
+        }
+    }
+
+    #[test]
+    fn io_error() {
+        let result = decode_input(&temp_path("missing.wav"), &Default::default(), |_| ());
+        assert!(matches!(result, Err(InputError::Io(_))));
+    }
+
+    #[test]
+    fn format_error() {
+        let path = temp_path("garbage.wav");
+        std::fs::write(&path, b"this isn't audio, no matter what the extension says").unwrap();
+        let result = decode_input(&path, &Default::default(), |_| ());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(InputError::Format(_))));
+    }
+
+    #[test]
+    fn no_audio_track() {
+        // only a video track
+        let mut params = CodecParameters::new();
+        params.for_codec(CODEC_TYPE_NULL);
+        assert!(matches!(select_track(&[Track::new(1, params)], None, None), Err(InputError::NoAudioTrack)));
+    }
+
+    #[test]
+    fn no_such_track() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sine.mkv");
+        let result = decode_input(&path, &InputOptions { track: Some(9), ..Default::default() }, |_| ());
+        assert!(matches!(result, Err(InputError::NoSuchTrack(9))));
+    }
+
+    #[test]
+    fn codec_error() {
+        // opus isn't one of the enabled codecs
+        let mut params = CodecParameters::new();
+        params.for_codec(CODEC_TYPE_OPUS);
+        assert!(matches!(make_decoder(&Track::new(1, params)), Err(InputError::Codec(_))));
+    }
+
+    #[test]
+    fn read_error() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(ErrorKind::BrokenPipe.into())
+            }
+        }
+
+        let format = RawFormat { encoding: RawEncoding::S16le, sample_rate: 8000, channels: 1 };
+        let result = decode_raw(Broken, &format, &Default::default(), |_| ());
+        assert!(matches!(result, Err(InputError::Read(_))));
+    }
+
+    #[test]
+    fn channels_error() {
+        let format = RawFormat { encoding: RawEncoding::S16le, sample_rate: 8000, channels: 1 };
+        let options = InputOptions { channel: super::ChannelSelection::Right, ..Default::default() };
+        let result = decode_raw(&[0u8; 16][..], &format, &options, |_| ());
+        assert!(matches!(result, Err(InputError::Channels(_))));
+    }
+
+    #[test]
+    fn g711() {
+        assert_eq!(mulaw_to_linear(0xFF), 0);
+        assert_eq!(mulaw_to_linear(0x80), 32124);
+        assert_eq!(mulaw_to_linear(0x00), -32124);
+        assert_eq!(alaw_to_linear(0xD5), 8);
+        assert_eq!(alaw_to_linear(0xAA), 32256);
+        assert_eq!(alaw_to_linear(0x2A), -32256);
+    }
+
+    fn resample(input: &[f32], quality: ResamplerQuality) -> (Vec<f32>, Duration) {
+        let start = Instant::now();
+        let mut output = Vec::new();
+        let mut resampler = StreamResampler::new(44100, quality);
+        resampler.process(input, &mut |samples| output.extend_from_slice(samples));
+        resampler.finish(&mut |samples| output.extend_from_slice(samples));
+        (output, start.elapsed())
+    }
+
+    #[test]
+    fn resamplers_line_up() {
+        let mut click = vec![0.0f32; 44100];
+        click[11025] = 1.0;
+        // 5 kHz is over the 4 kHz nyquist and would fold back down to 3 kHz
+        let too_high: Vec<f32> = (0..44100).map(|i| (2.0 * PI * 5000.0 * i as f32 / 44100.0).sin()).collect();
+
+        let mut times = Vec::new();
+        for quality in [ResamplerQuality::Fast, ResamplerQuality::Balanced, ResamplerQuality::Best] {
+            let (output, time) = resample(&click, quality);
+            assert_eq!(output.len(), 8000, "{quality:?}");
+            let peak = (0..output.len()).max_by(|a, b| output[*a].abs().total_cmp(&output[*b].abs())).unwrap();
+            assert!(peak.abs_diff(2000) <= 1, "{quality:?} click ended up at {peak}");
+
+            // at least 50 dB down, and the best one at least 100 dB
+            let (output, _) = resample(&too_high, quality);
+            let aliased = tone_amplitude(&output[800..7200], 3000.0);
+            let limit = if quality == ResamplerQuality::Best { 1e-5 } else { 3e-3 };
+            assert!(aliased < limit, "{quality:?} let {aliased} through");
+
+            times.push(time);
+        }
+        // otherwise there's no point in balanced
+        assert!(times[1] < times[2], "balanced took {:?}, best {:?}", times[1], times[2]);
+    }
+
+}
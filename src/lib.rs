@@ -0,0 +1,78 @@
+use std::{fs::File, io::{Read, Write}, path::Path};
+
+use speex_safe::NbSubmodeId;
+
+pub mod encoding;
+pub mod decoding;
+pub mod resource_parse;
+pub mod resource_write;
+pub mod input_decoding;
+pub mod ogg_speex;
+pub mod raw_speex;
+pub mod quality;
+pub mod filters;
+pub mod loudness;
+pub mod processing;
+pub mod silence;
+pub mod dynamics;
+pub mod denoise;
+pub mod eq;
+pub mod vocoder;
+pub mod generate;
+#[cfg(test)]
+mod test_util;
+
+/// `-` as a path means stdin for inputs and stdout for outputs
+pub fn is_std_stream(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Reads a whole file, or stdin for `-`
+pub fn read_input(path: &Path) -> Vec<u8> {
+    let mut data = Vec::new();
+    match is_std_stream(path) {
+        true => std::io::stdin().lock().read_to_end(&mut data).unwrap(),
+        false => File::open(path).unwrap().read_to_end(&mut data).unwrap(),
+    };
+    data
+}
+
+/// Writes a whole file, or stdout for `-`
+pub fn write_output(path: &Path, data: &[u8]) {
+    match is_std_stream(path) {
+        true => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(data).unwrap();
+            stdout.flush().unwrap();
+        }
+        false => File::create(path).unwrap().write_all(data).unwrap(),
+    }
+}
+
+pub const SPEEX_SAMPLE_RATE: u32 = 8000;
+pub const SAMPLE_COUNT: usize = 160;
+// see SPEEX_GET_LOOKAHEAD in libspeex/nb_celp.c
+const ENCODER_LOOKAHEAD: usize = 40;
+const DECODER_LOOKAHEAD: usize = 40;
+pub const CODEC_DELAY: usize = ENCODER_LOOKAHEAD + DECODER_LOOKAHEAD;
+
+// speex-safe doesn't support querying for submode bits-per-frame,
+// so we will just have to store this crap ourselves ¯\_(ツ)_/¯
+// values from here: https://github.com/xiph/speex/blob/1de1260d24e01224df5fbb8b92893106c89bb8de/libspeex/modes.c#L178
+const fn submode_bits_per_frame(submode: NbSubmodeId) -> u16 {
+    match submode {
+        NbSubmodeId::VocoderLike => 43,
+        NbSubmodeId::ExtremeLow => 79,
+        NbSubmodeId::VeryLow => 119,
+        NbSubmodeId::Low => 160,
+        NbSubmodeId::Medium => 220,
+        NbSubmodeId::High => 300,
+        NbSubmodeId::VeryHigh => 364,
+        NbSubmodeId::ExtremeHigh => 492,
+    }
+}
+
+// submodes over high crash the game lol
+const fn game_supports_submode(submode: NbSubmodeId) -> bool {
+    !matches!(submode, NbSubmodeId::VeryHigh | NbSubmodeId::ExtremeHigh)
+}
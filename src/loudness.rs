@@ -0,0 +1,180 @@
+use std::f64::consts::PI;
+
+use crate::filters::{filter_samples, k_weighting};
+use crate::SPEEX_SAMPLE_RATE;
+
+// ebu r128 / bs.1770 gating
+const BLOCK_SECONDS: f64 = 0.4;
+const BLOCK_OVERLAP: usize = 4;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+// true peak is measured on a 4x oversampled signal, like bs.1770 says
+const OVERSAMPLING: usize = 4;
+const INTERPOLATION_TAPS: isize = 12;
+
+#[derive(Clone, Copy)]
+pub enum Normalization {
+    /// Target sample peak in dBFS
+    Peak(f64),
+    /// Target integrated loudness in LUFS
+    Loudness(f64),
+}
+
+pub struct LoudnessStats {
+    /// Integrated loudness in LUFS, `None` if everything is below the gate
+    pub integrated: Option<f64>,
+    /// Sample peak in dBFS
+    pub peak: f64,
+    /// True peak in dBTP
+    pub true_peak: f64,
+}
+
+impl LoudnessStats {
+    pub fn measure(samples: &[f32]) -> Self {
+        Self {
+            integrated: integrated_loudness(samples),
+            peak: to_db(samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs())) as f64),
+            true_peak: to_db(true_peak(samples)),
+        }
+    }
+
+    fn print(&self, when: &str) {
+        let integrated = match self.integrated {
+            Some(loudness) => format!("{loudness:.1} LUFS"),
+            None => "silent".to_string(),
+        };
+        eprintln!("loudness {when}: {integrated}, peak {:.1} dBFS, true peak {:.1} dBTP", self.peak, self.true_peak);
+    }
+}
+
+fn to_db(value: f64) -> f64 {
+    20.0 * value.log10()
+}
+
+/// Integrated loudness of 8 kHz mono samples, as in ebu r128
+pub fn integrated_loudness(samples: &[f32]) -> Option<f64> {
+    let mut weighted = samples.to_vec();
+    filter_samples(&mut weighted, &mut k_weighting(SPEEX_SAMPLE_RATE));
+
+    let block_len = (BLOCK_SECONDS * SPEEX_SAMPLE_RATE as f64) as usize;
+    let hop = block_len / BLOCK_OVERLAP;
+    let mut powers = Vec::new();
+    let mut start = 0;
+    while start + block_len <= weighted.len() {
+        let block = &weighted[start..start + block_len];
+        powers.push(block.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / block_len as f64);
+        start += hop;
+    }
+
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let gated_average = |threshold: f64| {
+        let gated: Vec<f64> = powers.iter().copied().filter(|power| loudness(*power) > threshold).collect();
+        match gated.is_empty() {
+            true => None,
+            false => Some(gated.iter().sum::<f64>() / gated.len() as f64),
+        }
+    };
+
+    let relative_threshold = loudness(gated_average(ABSOLUTE_GATE)?) + RELATIVE_GATE;
+    gated_average(relative_threshold.max(ABSOLUTE_GATE)).map(loudness)
+}
+
+/// Highest absolute value between the samples, found by oversampling
+pub fn true_peak(samples: &[f32]) -> f64 {
+    let mut peak = 0.0f64;
+
+    for (n, sample) in samples.iter().enumerate() {
+        peak = peak.max(sample.abs() as f64);
+
+        for phase in 1..OVERSAMPLING {
+            let t = phase as f64 / OVERSAMPLING as f64;
+            let mut value = 0.0;
+            for j in 1 - INTERPOLATION_TAPS..=INTERPOLATION_TAPS {
+                let Some(sample) = samples.get((n as isize + j) as usize) else {
+                    continue;
+                };
+                // hann windowed sinc
+                let x = t - j as f64;
+                let window = 0.5 + 0.5 * (PI * x / INTERPOLATION_TAPS as f64).cos();
+                value += *sample as f64 * (PI * x).sin() / (PI * x) * window;
+            }
+            peak = peak.max(value.abs());
+        }
+    }
+
+    peak
+}
+
+/// Applies gain to hit the target, without letting the true peak go over `true_peak_ceiling` (dBTP).
+///
+/// Prints the loudness before and after.
+pub fn normalize(samples: &mut [f32], normalization: Normalization, true_peak_ceiling: f64) {
+    let before = LoudnessStats::measure(samples);
+    before.print("before");
+
+    if before.peak == f64::NEG_INFINITY {
+        eprintln!("input is silent, not normalizing");
+        return;
+    }
+
+    let gain = match normalization {
+        Normalization::Peak(target) => target - before.peak,
+        Normalization::Loudness(target) => match before.integrated {
+            Some(loudness) => target - loudness,
+            None => {
+                eprintln!("input is too quiet to measure, not normalizing");
+                return;
+            }
+        },
+    };
+    let gain = gain.min(true_peak_ceiling - before.true_peak);
+
+    let factor = 10f64.powf(gain / 20.0) as f32;
+    samples.iter_mut().for_each(|sample| *sample *= factor);
+
+    LoudnessStats::measure(samples).print("after");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::{integrated_loudness, normalize, LoudnessStats, Normalization};
+    use crate::test_util::sine;
+
+    #[test]
+    fn sine_reads_like_the_reference() {
+        // a -20 dBFS sine at 1 kHz is -23 LUFS, give or take the k-weighting at 1 kHz
+        let loudness = integrated_loudness(&sine(1000.0, 0.1, 24000)).unwrap();
+        assert!((loudness + 23.0).abs() < 0.5, "{loudness}");
+    }
+
+    #[test]
+    fn normalize_hits_the_target() {
+        let mut samples = sine(440.0, 0.05, 24000);
+        normalize(&mut samples, Normalization::Loudness(-18.0), -1.0);
+        let loudness = integrated_loudness(&samples).unwrap();
+        assert!((loudness + 18.0).abs() < 0.5, "{loudness}");
+    }
+
+    #[test]
+    fn normalize_respects_the_ceiling() {
+        let mut samples = sine(440.0, 0.5, 24000);
+        normalize(&mut samples, Normalization::Loudness(-3.0), -3.0);
+        let stats = LoudnessStats::measure(&samples);
+        assert!(stats.true_peak <= -3.0 + 1e-3, "{}", stats.true_peak);
+        // the ceiling is what stopped it
+        assert!(stats.integrated.unwrap() < -4.0);
+    }
+
+    #[test]
+    fn true_peak_finds_inter_sample_peaks() {
+        // a quarter of the sample rate shifted by 45 degrees never gets sampled at its peak
+        let samples: Vec<f32> = (0..8000).map(|i| 0.5 * (PI / 2.0 * i as f32 + PI / 4.0).sin()).collect();
+        let stats = LoudnessStats::measure(&samples);
+        // the samples only reach 0.5 / sqrt(2)
+        assert!((stats.peak + 9.03).abs() < 0.1, "{}", stats.peak);
+        assert!(stats.true_peak > stats.peak + 2.5, "{} {}", stats.true_peak, stats.peak);
+    }
+}
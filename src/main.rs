@@ -1,17 +1,23 @@
-use std::{fs::File, path::PathBuf};
+use std::{io::Cursor, path::{Path, PathBuf}, time::Duration};
 
-use clap::{Parser, Subcommand};
-use decoding::decode;
-use encoding::encode;
-use input_decoding::decode_input;
-use resource_parse::{Resrc, ResrcMethod, ResrcRevision};
-use speex_safe::NbSubmodeId;
-
-mod encoding;
-mod decoding;
-mod resource_parse;
-mod resource_write;
-mod input_decoding;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use voiptool::decoding::{decode, decode_samples, read_frames, write_wav};
+use voiptool::encoding::{encode, vop_payload, EncoderSettings, TailPadding, VopEncoder};
+use voiptool::input_decoding::{decode_input, ChannelSelection, InputOptions, RawEncoding, RawFormat, ResamplerQuality};
+use voiptool::ogg_speex::{export_ogg, import_ogg, SpeexImport};
+use voiptool::denoise::DenoiseOptions;
+use voiptool::dynamics::{report_clipping, CompressorOptions, GateOptions};
+use voiptool::eq::{EqOptions, EqPreset};
+use voiptool::generate::{generate, Signal, SignalOptions};
+use voiptool::loudness::Normalization;
+use voiptool::processing::{process, ProcessingOptions};
+use voiptool::quality::{auto_tune, band_levels, measure_encoded, BandLevels};
+use voiptool::raw_speex::{export_raw, import_raw};
+use voiptool::resource_parse::{Resrc, ResrcMethod, ResrcRevision};
+use voiptool::resource_write::{resource_data, write_resource};
+use voiptool::silence::{analyze_vop, SilenceOptions};
+use voiptool::vocoder::{PitchTimeOptions, Stretch};
+use voiptool::read_input;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -24,101 +30,679 @@ struct Cli {
 enum Commands {
     /// Encodes audio file to VOP
     Encode {
-        /// Input file path
+        /// Input file path, or - for stdin
         input: PathBuf,
-        /// Output file path
+        /// Output file path, or - for stdout
+        output: PathBuf,
+        #[command(flatten)]
+        input_options: InputArgs,
+        #[command(flatten)]
+        processing: Box<ProcessingArgs>,
+        #[command(flatten)]
+        encoder: EncoderArgs,
+        #[command(flatten)]
+        revision: RevisionArgs,
+        /// Decode the result and print how close it is to the input (snr, segmental snr, log-spectral distance)
+        #[arg(long, default_value_t = false)]
+        report: bool,
+        /// Pick the quality, complexity, vad and highpass settings that give the smallest file above --min-snr
+        #[arg(long, default_value_t = false)]
+        auto: bool,
+        /// Lowest acceptable segmental snr in dB for --auto
+        #[arg(long, default_value_t = 10.0)]
+        min_snr: f64,
+    },
+    /// Decodes a VOP file, runs it through the processing options and encodes it again.
+    /// The codec delay gets dropped on the way, so it doesn't end up 10 ms later every time
+    Reprocess {
+        /// Input file path, or - for stdin
+        input: PathBuf,
+        /// Output file path, or - for stdout
+        output: PathBuf,
+        #[command(flatten)]
+        processing: Box<ProcessingArgs>,
+        #[command(flatten)]
+        encoder: EncoderArgs,
+        #[command(flatten)]
+        revision: RevisionArgs,
+        /// Decode the result and print how close it is to the processed audio
+        #[arg(long, default_value_t = false)]
+        report: bool,
+        /// Decode as much as possible from corrupted files, skipping damaged parts
+        #[arg(long, default_value_t = false)]
+        salvage: bool,
+        /// Resampler to use for --pitch
+        #[arg(long, value_enum, default_value_t = ResamplerQuality::Balanced)]
+        resampler: ResamplerQuality,
+    },
+    /// Generates a test signal, as WAV if the output ends in .wav and as VOP otherwise
+    Generate {
+        signal: Signal,
+        /// Output file path, or - for stdout (always VOP)
         output: PathBuf,
-        /// Encoding quality (0 to 8, higher is better)
-        #[arg(short, long, default_value_t = 8)]
-        quality: i32,
-        /// Encoding complexity (0 to 10, higher is better and more CPU intensive)
-        #[arg(short, long, default_value_t = 10)]
-        complexity: i32,
-        /// Enable voice activity detection
-        #[arg(short, long, default_value_t = false)]
-        vad: bool,
-        /// Enable highpass filter
-        #[arg(short = 'f', long, default_value_t = false)]
-        highpass_filter: bool,
-        /// Resource revision
-        #[arg(short, long, default_value_t = 0x33e)]
-        revision: u32,
-        /// Resource branch ID
-        #[arg(long, default_value_t = 0x0)]
-        branch_id: u16,
-        /// Resource branch revision
-        #[arg(long, default_value_t = 0x0)]
-        branch_revision: u16,
+        /// Length of the signal, same format as --start
+        #[arg(long, value_parser = parse_time, default_value = "1")]
+        duration: Duration,
+        /// Peak level in dBFS
+        #[arg(long, allow_hyphen_values = true, default_value_t = -6.0)]
+        level: f64,
+        /// Frequency of the sine, or where the sweep starts (1000 Hz for sines and 100 Hz for sweeps by default)
+        #[arg(long, value_parser = parse_frequency)]
+        frequency: Option<f64>,
+        /// Where the sweep ends
+        #[arg(long, value_parser = parse_frequency, default_value_t = 3800.0)]
+        end_frequency: f64,
+        /// Dtmf digits, spread out evenly over the duration
+        #[arg(long, default_value = "123456789*0#")]
+        digits: String,
+        /// Time between clicks, in seconds
+        #[arg(long, value_parser = parse_time, default_value = "0.5")]
+        click_interval: Duration,
+        /// Seed for the noise generators
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
+        #[command(flatten)]
+        encoder: EncoderArgs,
+        #[command(flatten)]
+        revision: RevisionArgs,
     },
     /// Decodes VOP file to WAV
     Decode {
+        /// Input file path, or - for stdin
+        input: PathBuf,
+        /// Output file path, or - for stdout
+        output: PathBuf,
+        /// Decode as much as possible from corrupted files, skipping damaged parts
+        #[arg(long, default_value_t = false)]
+        salvage: bool,
+        /// Drop the leading samples added by the codec delay (for files encoded without compensation)
+        #[arg(long, default_value_t = false)]
+        compensate_delay: bool,
+    },
+    /// Exports the speex frames of a VOP file without re-encoding
+    Export {
+        /// Input file path
+        input: PathBuf,
+        /// Output file path
+        output: PathBuf,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = SpeexFormat::Ogg)]
+        format: SpeexFormat,
+    },
+    /// Reports how much silence could be cut from the start and the end of a VOP file
+    Silence {
+        /// Input file path
+        input: PathBuf,
+        #[command(flatten)]
+        silence: SilenceArgs,
+    },
+    /// Imports narrowband speex frames to VOP without re-encoding
+    Import {
         /// Input file path
         input: PathBuf,
         /// Output file path
         output: PathBuf,
+        /// Input format
+        #[arg(long, value_enum, default_value_t = SpeexFormat::Ogg)]
+        format: SpeexFormat,
+        /// Re-encode streams that can't be stored in a VOP as-is, instead of rejecting them
+        #[arg(long, default_value_t = false)]
+        reencode: bool,
+        #[command(flatten)]
+        encoder: EncoderArgs,
+        #[command(flatten)]
+        revision: RevisionArgs,
     },
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum SpeexFormat {
+    /// Ogg Speex (.spx)
+    Ogg,
+    /// Length-prefixed speex frames, with the VOP flags in a .json sidecar next to them
+    Raw,
+}
+
+#[derive(Args)]
+struct InputArgs {
+    /// Id of the track to encode, defaults to the first audio track
+    #[arg(long)]
+    track: Option<u32>,
+    /// Language of the audio track to encode (e.g. eng), for videos with more than one
+    #[arg(long, conflicts_with = "track")]
+    language: Option<String>,
+    /// Which channel to encode: left, right, a channel index (starting at 0), mid, side or mix
+    #[arg(long, default_value = "mix")]
+    channel: ChannelSelection,
+    /// Custom downmix, one weight per channel separated by commas (e.g. 0.3,0.3,0.4,0,0,0 for 5.1), overrides --channel
+    #[arg(long, value_delimiter = ',', allow_hyphen_values = true)]
+    downmix: Option<Vec<f32>>,
+    /// Where to start encoding, in seconds (e.g. 12.5) or as [hh:]mm:ss[.ms]
+    #[arg(long, value_parser = parse_time)]
+    start: Option<Duration>,
+    /// Where to stop encoding, same format as --start
+    #[arg(long, value_parser = parse_time, conflicts_with = "duration")]
+    end: Option<Duration>,
+    /// How much to encode after --start, same format as --start
+    #[arg(long, value_parser = parse_time)]
+    duration: Option<Duration>,
+    /// Resampler to use for inputs that aren't 8 kHz and for --pitch
+    #[arg(long, value_enum, default_value_t = ResamplerQuality::Balanced)]
+    resampler: ResamplerQuality,
+    /// Read the input as headerless pcm in this format
+    #[arg(long, value_enum)]
+    raw_format: Option<RawEncoding>,
+    /// Sample rate of raw pcm input
+    #[arg(long, default_value_t = 8000, requires = "raw_format", value_parser = clap::value_parser!(u32).range(1..))]
+    raw_rate: u32,
+    /// Number of interleaved channels in raw pcm input
+    #[arg(long, default_value_t = 1, requires = "raw_format", value_parser = clap::value_parser!(u16).range(1..=18))]
+    raw_channels: u16,
+}
+
+fn parse_frequency(s: &str) -> Result<f64, String> {
+    let freq: f64 = s.parse().map_err(|_| format!("invalid frequency {s}"))?;
+    // filters stop making sense at the 4 kHz nyquist limit
+    match freq > 0.0 && freq < 4000.0 {
+        true => Ok(freq),
+        false => Err(format!("frequency has to be between 0 and 4000 Hz, got {s}")),
+    }
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 && ratio.is_finite() => Ok(ratio),
+        _ => Err(format!("invalid ratio {s}, it has to be more than 0")),
+    }
+}
+
+fn parse_time(s: &str) -> Result<Duration, String> {
+    let mut seconds = 0.0;
+    for part in s.split(':') {
+        let value: f64 = part.parse().map_err(|_| format!("invalid time {s}"))?;
+        seconds = seconds * 60.0 + value;
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("invalid time {s}"))
+}
+
+impl From<InputArgs> for InputOptions {
+    fn from(args: InputArgs) -> Self {
+        Self {
+            track: args.track,
+            language: args.language,
+            channel: args.channel,
+            downmix: args.downmix,
+            start: args.start,
+            end: args.end.or(args.duration.map(|duration| args.start.unwrap_or_default() + duration)),
+            resampler: args.resampler,
+            raw: args.raw_format.map(|encoding| RawFormat {
+                encoding,
+                sample_rate: args.raw_rate,
+                channels: args.raw_channels,
+            }),
+        }
+    }
+}
+
+#[derive(Args)]
+struct ProcessingArgs {
+    /// Take out steady background noise (spectral subtraction)
+    #[arg(long, default_value_t = false)]
+    denoise: bool,
+    /// How much of the measured noise to take out, higher is cleaner but more artifacty
+    #[arg(long, default_value_t = 1.0)]
+    denoise_strength: f32,
+    /// Where to learn the noise from (with --noise-to), defaults to the quietest part of the input
+    #[arg(long, value_parser = parse_time, requires = "noise_to")]
+    noise_from: Option<Duration>,
+    /// Where the noise sample ends
+    #[arg(long, value_parser = parse_time, requires = "noise_from")]
+    noise_to: Option<Duration>,
+    /// Mute everything below --gate-threshold
+    #[arg(long, default_value_t = false)]
+    noise_gate: bool,
+    /// Level in dBFS the noise gate opens at
+    #[arg(long, allow_hyphen_values = true, default_value_t = -45.0)]
+    gate_threshold: f64,
+    /// How long the noise gate takes to open, in seconds
+    #[arg(long, value_parser = parse_time, default_value = "0.005")]
+    gate_attack: Duration,
+    /// How long the noise gate takes to close, in seconds
+    #[arg(long, value_parser = parse_time, default_value = "0.1")]
+    gate_release: Duration,
+    /// Cut the silence off the start and the end
+    #[arg(long, default_value_t = false)]
+    trim_silence: bool,
+    #[command(flatten)]
+    silence: SilenceArgs,
+    /// Shift the pitch by this many semitones (e.g. -3 or 4.5)
+    #[arg(long, allow_hyphen_values = true)]
+    pitch: Option<f64>,
+    /// Keep the formants where they are when shifting the pitch, so voices don't turn into chipmunks
+    #[arg(long, default_value_t = false, requires = "pitch")]
+    preserve_formants: bool,
+    /// Make the input this many times longer without changing the pitch (e.g. 0.8 to speed it up)
+    #[arg(long, value_parser = parse_ratio, conflicts_with = "fit_to")]
+    stretch: Option<f64>,
+    /// Stretch or squash the input to this length without changing the pitch, same format as --start
+    #[arg(long, value_parser = parse_time)]
+    fit_to: Option<Duration>,
+    /// Tone shaping preset for the narrow band speex keeps, on top of (or instead of) --highpass-filter
+    #[arg(long, value_enum)]
+    eq: Option<EqPreset>,
+    /// Low edge of the eq band-pass in Hz, overrides the preset
+    #[arg(long, value_parser = parse_frequency)]
+    eq_low_cut: Option<f64>,
+    /// High edge of the eq band-pass in Hz, overrides the preset
+    #[arg(long, value_parser = parse_frequency)]
+    eq_high_cut: Option<f64>,
+    /// Boost in dB around 2.5 kHz to make speech cut through, overrides the preset
+    #[arg(long, allow_hyphen_values = true)]
+    presence: Option<f64>,
+    /// Turn down sibilance over this many dBFS, overrides the preset
+    #[arg(long, allow_hyphen_values = true)]
+    de_esser: Option<f64>,
+    /// Compress the dynamics so quiet and loud parts are closer in level
+    #[arg(long, default_value_t = false)]
+    compress: bool,
+    /// Level in dBFS the compressor starts at
+    #[arg(long, allow_hyphen_values = true, default_value_t = -20.0)]
+    compressor_threshold: f64,
+    /// Compression ratio, 4 means 4 dB over the threshold come out as 1 dB
+    #[arg(long, default_value_t = 4.0)]
+    compressor_ratio: f64,
+    /// How fast the compressor reacts, in seconds
+    #[arg(long, value_parser = parse_time, default_value = "0.01")]
+    compressor_attack: Duration,
+    /// How fast the compressor lets go, in seconds
+    #[arg(long, value_parser = parse_time, default_value = "0.1")]
+    compressor_release: Duration,
+    /// Gain in dB to add after compressing
+    #[arg(long, allow_hyphen_values = true, default_value_t = 0.0)]
+    makeup_gain: f64,
+    /// Normalize so the highest sample peak is at this many dBFS
+    #[arg(long, allow_hyphen_values = true, conflicts_with = "normalize_loudness")]
+    normalize_peak: Option<f64>,
+    /// Normalize to this integrated loudness in LUFS (EBU R128)
+    #[arg(long, allow_hyphen_values = true)]
+    normalize_loudness: Option<f64>,
+    /// Highest true peak in dBTP normalization is allowed to reach
+    #[arg(long, allow_hyphen_values = true, default_value_t = -1.0)]
+    true_peak_ceiling: f64,
+    /// Keep peaks under this many dBFS with a lookahead limiter
+    #[arg(long, allow_hyphen_values = true)]
+    limiter: Option<f64>,
+    /// Round off peaks with a soft clipper
+    #[arg(long, default_value_t = false)]
+    soft_clip: bool,
+}
+
+impl From<ProcessingArgs> for ProcessingOptions {
+    fn from(args: ProcessingArgs) -> Self {
+        let normalization = match (args.normalize_peak, args.normalize_loudness) {
+            (Some(peak), _) => Some(Normalization::Peak(peak)),
+            (None, Some(loudness)) => Some(Normalization::Loudness(loudness)),
+            (None, None) => None,
+        };
+
+        let eq_set = args.eq.is_some()
+            || args.eq_low_cut.is_some()
+            || args.eq_high_cut.is_some()
+            || args.presence.is_some()
+            || args.de_esser.is_some();
+        let preset = args.eq.map(EqPreset::options).unwrap_or_default();
+        let eq = eq_set.then_some(EqOptions {
+            low_cut: args.eq_low_cut.or(preset.low_cut),
+            high_cut: args.eq_high_cut.or(preset.high_cut),
+            presence: args.presence.unwrap_or(preset.presence),
+            de_esser: args.de_esser.or(preset.de_esser),
+        });
+
+        let stretch = match (args.stretch, args.fit_to) {
+            (Some(ratio), _) => Some(Stretch::Ratio(ratio)),
+            (None, Some(duration)) => Some(Stretch::Fit(duration)),
+            (None, None) => None,
+        };
+        let pitch_time = (args.pitch.is_some() || stretch.is_some()).then_some(PitchTimeOptions {
+            semitones: args.pitch.unwrap_or(0.0),
+            preserve_formants: args.preserve_formants,
+            stretch,
+            // see processing_options()
+            resampler: ResamplerQuality::default(),
+        });
+
+        Self {
+            denoise: args.denoise.then_some(DenoiseOptions {
+                strength: args.denoise_strength,
+                profile: args.noise_from.zip(args.noise_to),
+            }),
+            noise_gate: args.noise_gate.then_some(GateOptions {
+                threshold: args.gate_threshold,
+                attack: args.gate_attack,
+                release: args.gate_release,
+            }),
+            trim_silence: args.trim_silence.then_some(args.silence.into()),
+            pitch_time,
+            eq,
+            compressor: args.compress.then_some(CompressorOptions {
+                threshold: args.compressor_threshold,
+                ratio: args.compressor_ratio,
+                attack: args.compressor_attack,
+                release: args.compressor_release,
+                makeup_gain: args.makeup_gain,
+            }),
+            normalization,
+            true_peak_ceiling: args.true_peak_ceiling,
+            limiter: args.limiter,
+            soft_clip: args.soft_clip,
+        }
+    }
+}
+
+/// Same as the plain conversion, but pitch shifting uses `resampler` (which the processing args don't have)
+fn processing_options(args: ProcessingArgs, resampler: ResamplerQuality) -> ProcessingOptions {
+    let mut options: ProcessingOptions = args.into();
+    if let Some(pitch_time) = &mut options.pitch_time {
+        pitch_time.resampler = resampler;
+    }
+    options
+}
+
+#[derive(Args)]
+struct SilenceArgs {
+    /// Anything quieter than this many dBFS counts as silence
+    #[arg(long, allow_hyphen_values = true, default_value_t = -50.0)]
+    silence_threshold: f64,
+    /// Silence shorter than this doesn't get cut, in seconds
+    #[arg(long, value_parser = parse_time, default_value = "0.1")]
+    min_silence: Duration,
+    /// How much silence to leave in on each end, in seconds
+    #[arg(long, value_parser = parse_time, default_value = "0.05")]
+    silence_padding: Duration,
+}
+
+impl From<SilenceArgs> for SilenceOptions {
+    fn from(args: SilenceArgs) -> Self {
+        Self {
+            threshold: args.silence_threshold,
+            min_duration: args.min_silence,
+            padding: args.silence_padding,
+        }
+    }
+}
+
+#[derive(Args)]
+struct EncoderArgs {
+    /// Encoding quality (0 to 8, higher is better)
+    #[arg(short, long, default_value_t = 8)]
+    quality: i32,
+    /// Encoding complexity (0 to 10, higher is better and more CPU intensive)
+    #[arg(short, long, default_value_t = 10)]
+    complexity: i32,
+    /// Enable voice activity detection
+    #[arg(short, long, default_value_t = false)]
+    vad: bool,
+    /// Enable highpass filter
+    #[arg(short = 'f', long, default_value_t = false)]
+    highpass_filter: bool,
+    /// Compensate for the codec delay, so the decoded audio lines up with the input
+    #[arg(long, default_value_t = false)]
+    compensate_delay: bool,
+    /// How to pad the last frame if the input doesn't fill it
+    #[arg(long, value_enum, default_value_t = TailPadding::Zero)]
+    tail_padding: TailPadding,
+    /// Number of silent frames (20 ms each) to add at the end
+    #[arg(long, default_value_t = 0)]
+    trailing_silence: usize,
+}
+
+impl EncoderArgs {
+    fn settings(&self) -> Option<EncoderSettings> {
+        if !(0..=8).contains(&self.quality) {
+            println!("Quality has to be between 0 and 8");
+            return None;
+        }
+
+        if !(0..=10).contains(&self.complexity) {
+            println!("Complexity has to be between 0 and 10");
+            return None;
+        }
+
+        Some(EncoderSettings {
+            quality: self.quality,
+            complexity: self.complexity,
+            vad: self.vad,
+            highpass_filter: self.highpass_filter,
+            compensate_delay: self.compensate_delay,
+            tail_padding: self.tail_padding,
+            trailing_silence: self.trailing_silence,
+        })
+    }
+}
+
+#[derive(Args)]
+struct RevisionArgs {
+    /// Resource revision
+    #[arg(short, long, default_value_t = 0x33e)]
+    revision: u32,
+    /// Resource branch ID
+    #[arg(long, default_value_t = 0x0)]
+    branch_id: u16,
+    /// Resource branch revision
+    #[arg(long, default_value_t = 0x0)]
+    branch_revision: u16,
+}
+
+impl From<RevisionArgs> for ResrcRevision {
+    fn from(args: RevisionArgs) -> Self {
+        Self {
+            head: args.revision,
+            branch_id: args.branch_id,
+            branch_revision: args.branch_revision,
+        }
+    }
+}
+
 
 fn main() {
     let cli = Cli::parse();
     
     match cli.command {
-        Commands::Encode {
-            input,
-            output,
-            quality,
-            complexity,
-            vad,
-            highpass_filter,
-            revision,
-            branch_id,
-            branch_revision
-        } => {
-            if !(0..=8).contains(&quality) {
-                println!("Quality has to be between 0 and 8");
+        Commands::Encode { input, output, input_options, processing, encoder, revision, report, auto, min_snr } => {
+            let Some(settings) = encoder.settings() else {
                 return;
-            }
+            };
+            let processing = processing_options(*processing, input_options.resampler);
 
-            if !(0..=10).contains(&complexity) {
-                println!("Complexity has to be between 0 and 10");
+            // the whole input only has to be kept around for processing, the report and auto tuning,
+            // otherwise it goes straight into the encoder
+            let keep_input = report || auto || !processing.is_empty();
+            let mut input_samples = Vec::new();
+            let mut encoder = (!keep_input).then(|| VopEncoder::new(&settings));
+            let decoded = decode_input(&input, &input_options.into(), |samples| {
+                match &mut encoder {
+                    Some(encoder) => encoder.push(samples),
+                    None => input_samples.extend_from_slice(samples),
+                }
+            });
+            if let Err(e) = decoded {
+                eprintln!("Can't read {}: {e}", input.display());
                 return;
             }
+            // the report shows what processing did to the spectrum, so it needs what came before it
+            let unprocessed_levels = (report && !processing.is_empty()).then(|| band_levels(&input_samples));
+            let input_samples = process(input_samples, &processing);
 
-            let revision = ResrcRevision {
-                head: revision,
-                branch_id,
-                branch_revision,
+            let revision = revision.into();
+            let (settings, data) = match encoder {
+                None if auto => match auto_tune(&input_samples, &settings, &revision, min_snr) {
+                    Some((settings, data)) => {
+                        report_clipping(input_samples.iter().filter(|sample| !(-1.0..=1.0).contains(*sample)).count());
+                        eprintln!(
+                            "chosen settings: --quality {} --complexity {}{}{} ({} bytes written)",
+                            settings.quality,
+                            settings.complexity,
+                            if settings.vad { " --vad" } else { "" },
+                            if settings.highpass_filter { " --highpass-filter" } else { "" },
+                            resource_data(&data, &revision).len(),
+                        );
+                        (settings, data)
+                    }
+                    None => {
+                        eprintln!("No settings reach a segmental snr of {min_snr} dB");
+                        return;
+                    }
+                },
+                encoder => {
+                    let mut encoder = encoder.unwrap_or_else(|| VopEncoder::new(&settings));
+                    encoder.push(&input_samples);
+                    report_clipping(encoder.clipped_samples());
+                    (settings, encoder.finish())
+                }
             };
 
-            let samples = decode_input(&input);
-            encode(samples, &output, quality, complexity, vad, highpass_filter, revision)
+            if report {
+                print_report(&input_samples, &data, &settings, unprocessed_levels);
+            }
+
+            write_resource(&output, data, revision);
         },
-        Commands::Decode { input, output } => {
-            let mut vop = File::open(input).unwrap();
-            let vop = Resrc::new(&mut vop);
-            if let ResrcMethod::Binary { resrc_type, data, .. } = vop.method {
-                assert!(resrc_type == *b"VOP");
-                decode(data, &output);
+        Commands::Reprocess { input, output, processing, encoder, revision, report, salvage, resampler } => {
+            let Some(settings) = encoder.settings() else {
+                return;
+            };
+            let Some(data) = read_vop(&input) else {
+                return;
+            };
+            let processing = processing_options(*processing, resampler);
+
+            // unless the encoder takes care of the delay, it gets dropped here
+            let samples = match decode_samples(&data, salvage, !settings.compensate_delay) {
+                Ok((samples, salvage_report)) => {
+                    if salvage {
+                        eprintln!("{salvage_report}");
+                    }
+                    samples
+                }
+                Err(e) => {
+                    eprintln!("Can't decode {}: {e}", input.display());
+                    return;
+                }
+            };
+            let unprocessed_levels = report.then(|| band_levels(&samples));
+            let samples = process(samples, &processing);
+
+            let mut encoder = VopEncoder::new(&settings);
+            encoder.push(&samples);
+            report_clipping(encoder.clipped_samples());
+            let data = encoder.finish();
+
+            if report {
+                print_report(&samples, &data, &settings, unprocessed_levels);
+            }
+
+            write_resource(&output, data, revision.into());
+        }
+        Commands::Generate {
+            signal,
+            output,
+            duration,
+            level,
+            frequency,
+            end_frequency,
+            digits,
+            click_interval,
+            seed,
+            encoder,
+            revision,
+        } => {
+            let options = SignalOptions {
+                level,
+                frequency: frequency.unwrap_or(if signal == Signal::Sweep { 100.0 } else { 1000.0 }),
+                end_frequency,
+                digits,
+                click_interval,
+                seed,
+            };
+            let samples = generate(signal, duration, &options);
+
+            let is_wav = output.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("wav"));
+            match is_wav {
+                true => write_wav(samples.into_iter(), &output),
+                false => {
+                    let Some(settings) = encoder.settings() else {
+                        return;
+                    };
+                    encode(samples, &output, &settings, revision.into());
+                }
+            }
+        }
+        Commands::Decode { input, output, salvage, compensate_delay } => {
+            if let Some(data) = read_vop(&input) {
+                match decode(&data, &output, salvage, compensate_delay) {
+                    Ok(report) if salvage => eprintln!("{report}"),
+                    Ok(_) => (),
+                    Err(e) => eprintln!("Can't decode {}: {e}", input.display()),
+                }
+            }
+        }
+        Commands::Silence { input, silence } => {
+            if let Some(data) = read_vop(&input) {
+                if let Err(e) = analyze_vop(&data, &silence.into()) {
+                    eprintln!("Can't analyze {}: {e}", input.display());
+                }
             }
         }
+        Commands::Export { input, output, format } => {
+            if let Some(data) = read_vop(&input) {
+                let frames = match read_frames(&data, false) {
+                    Ok((frames, _)) => frames,
+                    Err(e) => {
+                        eprintln!("Can't export {}: {e}", input.display());
+                        return;
+                    }
+                };
+                match format {
+                    SpeexFormat::Ogg => export_ogg(&frames, &output),
+                    SpeexFormat::Raw => export_raw(&frames, &output),
+                }
+            }
+        }
+        Commands::Import { input, output, format, reencode, encoder, revision } => {
+            let Some(settings) = encoder.settings() else {
+                return;
+            };
+
+            let imported = match format {
+                SpeexFormat::Ogg => import_ogg(&input, reencode),
+                SpeexFormat::Raw => import_raw(&input, reencode),
+            };
+
+            match imported {
+                Ok(SpeexImport::Frames(frames)) => write_resource(&output, vop_payload(frames), revision.into()),
+                Ok(SpeexImport::Samples(samples)) => encode(samples, &output, &settings, revision.into()),
+                Err(e) => println!("Can't import {}: {e}", input.display()),
+            }
+        }
+    }
+}
+
+fn print_report(input_samples: &[f32], data: &[u8], settings: &EncoderSettings, unprocessed_levels: Option<BandLevels>) {
+    let mut quality = measure_encoded(input_samples, data, settings);
+    if let Some(levels) = unprocessed_levels {
+        quality.band_levels.insert(0, ("unprocessed", levels));
     }
+    quality.print();
 }
 
-const SAMPLE_COUNT: usize = 160;
-
-// speex-safe doesn't support querying for submode bits-per-frame,
-// so we will just have to store this crap ourselves ¯\_(ツ)_/¯
-// values from here: https://github.com/xiph/speex/blob/1de1260d24e01224df5fbb8b92893106c89bb8de/libspeex/modes.c#L178
-const fn submode_bits_per_frame(submode: NbSubmodeId) -> u16 {
-    match submode {
-        NbSubmodeId::VocoderLike => 43,
-        NbSubmodeId::ExtremeLow => 79,
-        NbSubmodeId::VeryLow => 119,
-        NbSubmodeId::Low => 160,
-        NbSubmodeId::Medium => 220,
-        NbSubmodeId::High => 300,
-        NbSubmodeId::VeryHigh => 364,
-        NbSubmodeId::ExtremeHigh => 492,
+fn read_vop(path: &Path) -> Option<Vec<u8>> {
+    let mut vop = Cursor::new(read_input(path));
+    let vop = Resrc::new(&mut vop);
+    match vop.method {
+        ResrcMethod::Binary { resrc_type, data, .. } => {
+            assert!(resrc_type == *b"VOP");
+            Some(data)
+        }
+        ResrcMethod::Null => None,
     }
 }
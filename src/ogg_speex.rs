@@ -0,0 +1,342 @@
+use std::{fs::File, io::{BufReader, BufWriter, Cursor, Write}, path::Path};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+use speex_safe::{DynamicDecoder, ModeId, NbSubmodeId, SpeexBits};
+
+use crate::decoding::Frame;
+use crate::input_decoding::{resample, ResamplerQuality};
+use crate::{game_supports_submode, submode_bits_per_frame, ENCODER_LOOKAHEAD, SAMPLE_COUNT, SPEEX_SAMPLE_RATE};
+
+// there's only one logical stream in the file, so any serial will do
+const STREAM_SERIAL: u32 = 0x564f50;
+
+// how many speex packets go into a page, same as speexenc
+const PACKETS_PER_PAGE: usize = 50;
+
+// values from here: https://github.com/xiph/speex/blob/1de1260d24e01224df5fbb8b92893106c89bb8de/libspeex/modes.c#L364
+const NB_MODE_ID: i32 = 0;
+const NB_BITSTREAM_VERSION: i32 = 4;
+const SPEEX_HEADER_SIZE: i32 = 80;
+
+const SPEEX_MAGIC: &[u8; 8] = b"Speex   ";
+
+pub enum SpeexImport {
+    /// Frames that can go into a vop as-is, each with its flags byte
+    Frames(Vec<u8>),
+    /// Decoded 8 kHz samples, for streams that have to be re-encoded
+    Samples(Vec<f32>),
+}
+
+// the parts of SpeexHeader we care about
+struct StreamInfo {
+    rate: u32,
+    mode: i32,
+    channels: i32,
+    frames_per_packet: i32,
+    extra_headers: i32,
+}
+
+/// Writes the speex frames of a vop into an ogg speex file, without re-encoding them
+pub fn export_ogg(frames: &[Frame], output: &Path) {
+    let out = BufWriter::new(File::create(output).unwrap());
+    let mut writer = PacketWriter::new(out);
+
+    // a vop can switch submodes between frames when vad is on
+    let vbr = frames.windows(2).any(|pair| pair[0].flags & 0x3F != pair[1].flags & 0x3F);
+
+    writer.write_packet(speex_header(vbr).into_boxed_slice(), STREAM_SERIAL, PacketWriteEndInfo::EndPage, 0).unwrap();
+    let end_info = match frames.is_empty() {
+        true => PacketWriteEndInfo::EndStream,
+        false => PacketWriteEndInfo::EndPage,
+    };
+    writer.write_packet(comment_header().into_boxed_slice(), STREAM_SERIAL, end_info, 0).unwrap();
+
+    for (i, frame) in frames.iter().enumerate() {
+        // granule positions don't count the encoder lookahead, same as speexenc
+        let granule = ((i + 1) * SAMPLE_COUNT).saturating_sub(ENCODER_LOOKAHEAD) as u64;
+
+        let end_info = if i == frames.len() - 1 {
+            PacketWriteEndInfo::EndStream
+        } else if (i + 1) % PACKETS_PER_PAGE == 0 {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+
+        writer.write_packet(frame.data.into(), STREAM_SERIAL, end_info, granule).unwrap();
+    }
+
+    writer.into_inner().flush().unwrap();
+}
+
+// see SpeexHeader in speex_header.h
+fn speex_header(vbr: bool) -> Vec<u8> {
+    let mut header = Vec::with_capacity(SPEEX_HEADER_SIZE as usize);
+
+    header.write_all(SPEEX_MAGIC).unwrap();
+    let mut version = [0u8; 20];
+    version[..11].copy_from_slice(b"speex-1.2.1");
+    header.write_all(&version).unwrap();
+
+    header.write_i32::<LittleEndian>(1).unwrap(); // speex version id
+    header.write_i32::<LittleEndian>(SPEEX_HEADER_SIZE).unwrap();
+    header.write_i32::<LittleEndian>(8000).unwrap(); // sample rate
+    header.write_i32::<LittleEndian>(NB_MODE_ID).unwrap();
+    header.write_i32::<LittleEndian>(NB_BITSTREAM_VERSION).unwrap();
+    header.write_i32::<LittleEndian>(1).unwrap(); // channels
+    header.write_i32::<LittleEndian>(-1).unwrap(); // bitrate, unknown
+    header.write_i32::<LittleEndian>(SAMPLE_COUNT as i32).unwrap();
+    header.write_i32::<LittleEndian>(vbr as i32).unwrap();
+    header.write_i32::<LittleEndian>(1).unwrap(); // frames per packet
+    header.write_i32::<LittleEndian>(0).unwrap(); // extra headers
+    header.write_i32::<LittleEndian>(0).unwrap(); // reserved
+    header.write_i32::<LittleEndian>(0).unwrap(); // reserved
+
+    header
+}
+
+// vorbis comment header with just the vendor string
+fn comment_header() -> Vec<u8> {
+    let vendor = concat!("voiptool ", env!("CARGO_PKG_VERSION"));
+
+    let mut header = Vec::new();
+    header.write_u32::<LittleEndian>(vendor.len() as u32).unwrap();
+    header.write_all(vendor.as_bytes()).unwrap();
+    header.write_u32::<LittleEndian>(0).unwrap(); // number of comments
+
+    header
+}
+
+fn parse_speex_header(packet: &[u8]) -> Result<StreamInfo, String> {
+    if packet.len() < SPEEX_HEADER_SIZE as usize || &packet[..8] != SPEEX_MAGIC {
+        return Err("not an ogg speex file".to_string());
+    }
+
+    let mut header = Cursor::new(&packet[36..]);
+    let rate = header.read_i32::<LittleEndian>().unwrap();
+    let mode = header.read_i32::<LittleEndian>().unwrap();
+    let _bitstream_version = header.read_i32::<LittleEndian>().unwrap();
+    let channels = header.read_i32::<LittleEndian>().unwrap();
+    let _bitrate = header.read_i32::<LittleEndian>().unwrap();
+    let _frame_size = header.read_i32::<LittleEndian>().unwrap();
+    let _vbr = header.read_i32::<LittleEndian>().unwrap();
+    let frames_per_packet = header.read_i32::<LittleEndian>().unwrap().max(1);
+    let extra_headers = header.read_i32::<LittleEndian>().unwrap().max(0);
+
+    if !(0..=2).contains(&mode) {
+        return Err(format!("unknown speex mode {mode}"));
+    }
+    if rate <= 0 {
+        return Err(format!("invalid sample rate {rate}"));
+    }
+
+    Ok(StreamInfo {
+        rate: rate as u32,
+        mode,
+        channels,
+        frames_per_packet,
+        extra_headers,
+    })
+}
+
+// checks if a packet is a single narrowband frame the game can play, and returns its submode
+fn vop_compatible_submode(packet: &[u8]) -> Result<NbSubmodeId, String> {
+    let first_byte = *packet.first().ok_or("packet is empty")?;
+
+    // narrowband frames start with the wideband bit (always 0) and the 4-bit submode
+    if first_byte & 0x80 != 0 {
+        return Err("contains a wideband layer".to_string());
+    }
+    let submode = first_byte >> 3;
+    if !(1..=8).contains(&submode) {
+        return Err(format!("uses submode {submode}, which can't be stored in a vop"));
+    }
+
+    let submode = NbSubmodeId::from(submode as i32);
+    if !game_supports_submode(submode) {
+        return Err(format!("uses submode {submode:?}, which crashes the game"));
+    }
+
+    let bytes_per_frame = (submode_bits_per_frame(submode) as usize + 7) >> 3;
+    if packet.len() != bytes_per_frame {
+        return Err("contains more than one frame".to_string());
+    }
+
+    Ok(submode)
+}
+
+/// Reads an ogg speex file.
+///
+/// Narrowband streams with one game-compatible frame per packet are repackaged as-is,
+/// anything else is rejected, or decoded for re-encoding if `reencode` is set.
+pub fn import_ogg(input: &Path, reencode: bool) -> Result<SpeexImport, String> {
+    let file = File::open(input).map_err(|e| e.to_string())?;
+    let mut reader = PacketReader::new(BufReader::new(file));
+
+    // multiplexed files can start with headers of other streams, stick to the first speex one
+    let (serial, info) = loop {
+        let packet = reader.read_packet().map_err(|e| e.to_string())?.ok_or("not an ogg speex file")?;
+        if packet.data.starts_with(SPEEX_MAGIC) {
+            break (packet.stream_serial(), parse_speex_header(&packet.data)?);
+        }
+    };
+
+    // comment header, then the extra headers
+    let mut headers_left = 1 + info.extra_headers;
+    while headers_left > 0 {
+        let packet = reader.read_packet().map_err(|e| e.to_string())?.ok_or("file ends in the headers")?;
+        if packet.stream_serial() == serial {
+            headers_left -= 1;
+        }
+    }
+
+    let mut packets = Vec::new();
+    while let Some(packet) = reader.read_packet().map_err(|e| e.to_string())? {
+        if packet.stream_serial() == serial && !packet.data.is_empty() {
+            packets.push(packet.data);
+        }
+    }
+
+    let problem = match info.mode {
+        0 if info.channels != 1 => Some(format!("stream has {} channels", info.channels)),
+        0 if info.frames_per_packet != 1 => Some(format!("stream has {} frames per packet", info.frames_per_packet)),
+        0 => None,
+        _ => Some("stream isn't narrowband".to_string()),
+    };
+
+    let frames = match problem {
+        Some(problem) => Err(problem),
+        None => repackage_frames(&packets, None),
+    };
+
+    match frames {
+        Ok(frames) => Ok(SpeexImport::Frames(frames)),
+        Err(problem) if reencode => {
+            eprintln!("{problem}, re-encoding");
+            let mode = ModeId::from(info.mode);
+            Ok(SpeexImport::Samples(decode_packets(&packets, mode, info.frames_per_packet, info.rate)))
+        }
+        Err(problem) => Err(format!("{problem}, use --reencode to re-encode it")),
+    }
+}
+
+/// Adds a flags byte to each speex packet, if they can all go into a vop as-is.
+///
+/// Without `flags`, they're set the same way the encoder does: if the submode switches around
+/// vad was on and only high frames count as speech, otherwise everything does.
+pub fn repackage_frames(packets: &[Vec<u8>], flags: Option<&[u8]>) -> Result<Vec<u8>, String> {
+    let submodes = packets
+        .iter()
+        .enumerate()
+        .map(|(i, packet)| vop_compatible_submode(packet).map_err(|reason| format!("packet {i} {reason}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let vad = submodes.windows(2).any(|pair| pair[0] != pair[1]);
+
+    let mut frames = Vec::new();
+    for (i, (packet, submode)) in packets.iter().zip(submodes).enumerate() {
+        let frame_flags = match flags {
+            // the vop decoder goes by the submode in the flags to find where the next frame starts
+            Some(flags) if flags[i] & 0x3F != (submode as i32) as u8 => {
+                return Err(format!("packet {i} uses submode {submode:?}, but its flags say submode {}", flags[i] & 0x3F));
+            }
+            Some(flags) => flags[i],
+            None if vad && submode != NbSubmodeId::High => (submode as i32) as u8,
+            None => (submode as i32) as u8 | 0x80,
+        };
+        frames.push(frame_flags);
+        frames.extend_from_slice(packet);
+    }
+
+    Ok(frames)
+}
+
+/// Decodes speex packets into 8 kHz samples, for re-encoding them
+pub fn decode_packets(packets: &[Vec<u8>], mode: ModeId, frames_per_packet: i32, rate: u32) -> Vec<f32> {
+    let mut decoder = DynamicDecoder::new(mode);
+
+    let mut samples = Vec::new();
+    for packet in packets {
+        let mut packet = packet.clone();
+        let mut bits = SpeexBits::new();
+        bits.read_from(&mut packet);
+
+        for _ in 0..frames_per_packet {
+            match decoder.decode_to_owned(&mut bits) {
+                Ok(frame) => samples.extend(frame.iter().map(|value| value / 32768.0)),
+                Err(_) => break,
+            }
+        }
+    }
+
+    match rate == SPEEX_SAMPLE_RATE {
+        true => samples,
+        false => resample(samples, rate, ResamplerQuality::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, path::Path};
+
+    use ogg::{PacketWriteEndInfo, PacketWriter};
+
+    use super::{comment_header, export_ogg, import_ogg, speex_header, SpeexImport, STREAM_SERIAL};
+    use crate::decoding::read_frames;
+    use crate::encoding::{encode_data, EncoderSettings};
+    use crate::test_util::{temp_path, tone_then_silence};
+
+    fn import_and_clean_up(path: &Path) -> Vec<u8> {
+        let imported = import_ogg(path, false);
+        std::fs::remove_file(path).unwrap();
+        match imported.unwrap() {
+            SpeexImport::Frames(frames) => frames,
+            SpeexImport::Samples(_) => panic!("frames were re-encoded"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        // with vad the flags have to be worked out the same way the encoder sets them,
+        // speech frames are only high ones at the top qualities
+        for (vad, quality) in [(false, 8), (true, 8), (true, 4)] {
+            let data = encode_data(tone_then_silence(), &EncoderSettings { vad, quality, ..Default::default() });
+            let (frames, _) = read_frames(&data, false).unwrap();
+
+            let path = temp_path(&format!("round_trip_{vad}_{quality}.ogg"));
+            export_ogg(&frames, &path);
+            assert!(import_and_clean_up(&path) == data[frames[0].offset..], "vad: {vad}, quality: {quality}");
+        }
+    }
+
+    #[test]
+    fn other_streams_get_skipped() {
+        let data = encode_data(tone_then_silence(), &Default::default());
+        let (frames, _) = read_frames(&data, false).unwrap();
+
+        // another stream that starts first and has packets in between the speex ones
+        let path = temp_path("multiplexed.ogg");
+        let mut writer = PacketWriter::new(File::create(&path).unwrap());
+        let other = STREAM_SERIAL + 1;
+        writer.write_packet(b"OpusHead"[..].into(), other, PacketWriteEndInfo::EndPage, 0).unwrap();
+        writer.write_packet(speex_header(false).into(), STREAM_SERIAL, PacketWriteEndInfo::EndPage, 0).unwrap();
+        writer.write_packet(b"OpusTags"[..].into(), other, PacketWriteEndInfo::EndPage, 0).unwrap();
+        writer.write_packet(comment_header().into(), STREAM_SERIAL, PacketWriteEndInfo::EndPage, 0).unwrap();
+        for (i, frame) in frames.iter().enumerate() {
+            let end_info = match i == frames.len() - 1 {
+                true => PacketWriteEndInfo::EndStream,
+                false => PacketWriteEndInfo::EndPage,
+            };
+            writer.write_packet(vec![0xAA; 10].into(), other, PacketWriteEndInfo::EndPage, i as u64).unwrap();
+            writer.write_packet(frame.data.into(), STREAM_SERIAL, end_info, i as u64).unwrap();
+        }
+        drop(writer);
+
+        assert!(import_and_clean_up(&path) == data[frames[0].offset..]);
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(import_ogg(&temp_path("missing.ogg"), false).is_err());
+    }
+}
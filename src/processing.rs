@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use crate::denoise::{denoise, DenoiseOptions};
+use crate::dynamics::{compress, limit, noise_gate, soft_clip, CompressorOptions, GateOptions};
+use crate::eq::{equalize, EqOptions};
+use crate::loudness::{normalize, Normalization};
+use crate::silence::{trim_silence, SilenceOptions};
+use crate::vocoder::{pitch_time, PitchTimeOptions};
+
+const LIMITER_LOOKAHEAD: Duration = Duration::from_millis(5);
+const LIMITER_RELEASE: Duration = Duration::from_millis(50);
+
+/// What to do to the input samples before encoding
+#[derive(Clone)]
+pub struct ProcessingOptions {
+    pub denoise: Option<DenoiseOptions>,
+    pub noise_gate: Option<GateOptions>,
+    pub trim_silence: Option<SilenceOptions>,
+    pub pitch_time: Option<PitchTimeOptions>,
+    pub eq: Option<EqOptions>,
+    pub compressor: Option<CompressorOptions>,
+    pub normalization: Option<Normalization>,
+    /// Highest true peak normalization is allowed to reach, in dBTP
+    pub true_peak_ceiling: f64,
+    /// Ceiling for the lookahead limiter in dBFS
+    pub limiter: Option<f64>,
+    pub soft_clip: bool,
+}
+
+impl Default for ProcessingOptions {
+    fn default() -> Self {
+        Self {
+            denoise: None,
+            noise_gate: None,
+            trim_silence: None,
+            pitch_time: None,
+            eq: None,
+            compressor: None,
+            normalization: None,
+            true_peak_ceiling: -1.0,
+            limiter: None,
+            soft_clip: false,
+        }
+    }
+}
+
+impl ProcessingOptions {
+    /// Whether there's anything to do at all, if not the input can be streamed straight into the encoder
+    pub fn is_empty(&self) -> bool {
+        self.denoise.is_none()
+            && self.noise_gate.is_none()
+            && self.trim_silence.is_none()
+            && self.pitch_time.is_none()
+            && self.eq.is_none()
+            && self.compressor.is_none()
+            && self.normalization.is_none()
+            && self.limiter.is_none()
+            && !self.soft_clip
+    }
+}
+
+/// Runs the 8 kHz input samples through everything in `options`
+pub fn process(mut samples: Vec<f32>, options: &ProcessingOptions) -> Vec<f32> {
+    // denoising goes first, the noise profile range is in input time
+    if let Some(denoise_options) = &options.denoise {
+        denoise(&mut samples, denoise_options);
+    }
+
+    if let Some(gate) = &options.noise_gate {
+        noise_gate(&mut samples, gate);
+    }
+
+    if let Some(silence) = &options.trim_silence {
+        samples = trim_silence(samples, silence);
+    }
+
+    // after trimming, so fitting to a length only has the actual line to work with
+    if let Some(pitch_time_options) = &options.pitch_time {
+        samples = pitch_time(&samples, pitch_time_options);
+    }
+
+    // tone before dynamics, so the compressor and normalization get to see what actually gets encoded
+    if let Some(eq) = &options.eq {
+        equalize(&mut samples, eq);
+    }
+
+    if let Some(compressor) = &options.compressor {
+        compress(&mut samples, compressor);
+    }
+
+    if let Some(normalization) = options.normalization {
+        normalize(&mut samples, normalization, options.true_peak_ceiling);
+    }
+
+    // peak control goes last so nothing after it can push the level back up
+    if let Some(ceiling) = options.limiter {
+        limit(&mut samples, ceiling, LIMITER_LOOKAHEAD, LIMITER_RELEASE);
+    }
+
+    if options.soft_clip {
+        soft_clip(&mut samples);
+    }
+
+    samples
+}
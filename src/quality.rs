@@ -0,0 +1,336 @@
+use realfft::RealFftPlanner;
+
+use crate::decoding::decode_samples;
+use crate::encoding::{EncoderSettings, VopEncoder};
+use crate::resource_parse::ResrcRevision;
+use crate::resource_write::resource_data;
+use crate::{SAMPLE_COUNT, SPEEX_SAMPLE_RATE};
+
+const FFT_SIZE: usize = 256;
+
+// frames quieter than -60 dBFS don't count towards segmental snr and lsd,
+// otherwise silence would drag everything down
+const SILENCE_ENERGY: f64 = 1e-6;
+
+// usual limits for segmental snr, so a few perfect or awful frames don't dominate the average
+const MIN_SEGMENT_SNR: f64 = -10.0;
+const MAX_SEGMENT_SNR: f64 = 35.0;
+
+// lowest energy snr works with, -100 dB for a single sample
+const ENERGY_FLOOR: f64 = 1e-10;
+
+/// Edges of the bands the report shows levels for, in Hz
+const BANDS: [(f64, f64); 5] = [(0.0, 300.0), (300.0, 1000.0), (1000.0, 2000.0), (2000.0, 3400.0), (3400.0, 4000.0)];
+
+/// Level in dB of each of the report bands
+pub type BandLevels = [f64; BANDS.len()];
+
+pub struct Metrics {
+    /// Signal-to-noise ratio in dB
+    pub snr: f64,
+    /// Average per-frame snr in dB, over the frames that aren't silent
+    pub segmental_snr: Option<f64>,
+    /// Average log-spectral distance in dB, over the frames that aren't silent
+    pub log_spectral_distance: Option<f64>,
+}
+
+pub struct QualityReport {
+    pub overall: Metrics,
+    pub per_second: Vec<Metrics>,
+    /// Level in dB of each of the report bands, for the input, the decoded audio and whatever else gets added
+    pub band_levels: Vec<(&'static str, BandLevels)>,
+}
+
+struct FrameStats {
+    signal_energy: f64,
+    noise_energy: f64,
+    active: bool,
+    log_spectral_distance: f64,
+}
+
+/// Compares decoded audio with the (8 kHz) input it was encoded from.
+///
+/// Both have to be lined up already, see [`crate::CODEC_DELAY`].
+pub fn measure(reference: &[f32], decoded: &[f32]) -> QualityReport {
+    let len = reference.len().min(decoded.len());
+
+    let mut planner = RealFftPlanner::<f64>::new();
+    let fft = planner.plan_fft_forward(FFT_SIZE);
+    let mut input = fft.make_input_vec();
+    let mut reference_spectrum = fft.make_output_vec();
+    let mut decoded_spectrum = fft.make_output_vec();
+
+    let window: Vec<f64> = (0..SAMPLE_COUNT)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / SAMPLE_COUNT as f64).cos())
+        .collect();
+
+    let mut power_spectrum = |samples: &[f32], spectrum: &mut Vec<realfft::num_complex::Complex<f64>>| {
+        input.fill(0.0);
+        for (i, sample) in samples.iter().enumerate() {
+            input[i] = *sample as f64 * window[i];
+        }
+        fft.process(&mut input, spectrum).unwrap();
+    };
+
+    let mut frames = Vec::new();
+    for start in (0..len).step_by(SAMPLE_COUNT) {
+        let end = (start + SAMPLE_COUNT).min(len);
+        let reference = &reference[start..end];
+        let decoded = &decoded[start..end];
+
+        let signal_energy: f64 = reference.iter().map(|s| (*s as f64).powi(2)).sum();
+        let noise_energy: f64 = reference.iter().zip(decoded)
+            .map(|(r, d)| (*r as f64 - *d as f64).powi(2))
+            .sum();
+        let active = signal_energy / reference.len() as f64 > SILENCE_ENERGY;
+
+        let mut log_spectral_distance = 0.0;
+        if active {
+            power_spectrum(reference, &mut reference_spectrum);
+            power_spectrum(decoded, &mut decoded_spectrum);
+
+            let mut sum = 0.0;
+            for (r, d) in reference_spectrum.iter().zip(&decoded_spectrum) {
+                let difference = 10.0 * ((r.norm_sqr() + 1e-10) / (d.norm_sqr() + 1e-10)).log10();
+                sum += difference * difference;
+            }
+            log_spectral_distance = (sum / reference_spectrum.len() as f64).sqrt();
+        }
+
+        frames.push(FrameStats { signal_energy, noise_energy, active, log_spectral_distance });
+    }
+
+    let frames_per_second = SPEEX_SAMPLE_RATE as usize / SAMPLE_COUNT;
+    QualityReport {
+        overall: metrics(&frames),
+        per_second: frames.chunks(frames_per_second).map(metrics).collect(),
+        band_levels: vec![("input", band_levels(reference)), ("decoded", band_levels(decoded))],
+    }
+}
+
+/// Decodes freshly encoded vop data and compares it with the input it was encoded from
+pub fn measure_encoded(input: &[f32], data: &[u8], settings: &EncoderSettings) -> QualityReport {
+    // the delay has to go somewhere, if the encoder didn't take care of it the decoder has to
+    // it was just encoded, so it can't be damaged
+    let (decoded, _) = decode_samples(data, false, !settings.compensate_delay).unwrap();
+    measure(input, &decoded)
+}
+
+struct Candidate {
+    settings: EncoderSettings,
+    data: Vec<u8>,
+    segmental_snr: f64,
+    /// Size of the compressed resource, which is what actually ends up on disk
+    size: usize,
+}
+
+/// Finds the settings with the smallest resource that still has a segmental snr of at least `min_segmental_snr`,
+/// and returns them along with their encoded data.
+///
+/// A higher quality never sounds worse, so for each vad and highpass combination the lowest quality that passes
+/// at full complexity gets binary searched, and only that quality gets tried with the other complexities.
+/// The rest of the settings are taken from `base`.
+pub fn auto_tune(
+    input: &[f32],
+    base: &EncoderSettings,
+    revision: &ResrcRevision,
+    min_segmental_snr: f64,
+) -> Option<(EncoderSettings, Vec<u8>)> {
+    let try_settings = |settings: EncoderSettings| {
+        let mut encoder = VopEncoder::new(&settings);
+        encoder.push(input);
+        let data = encoder.finish();
+
+        // silence passes no matter what
+        let segmental_snr = measure_encoded(input, &data, &settings).overall.segmental_snr.unwrap_or(f64::INFINITY);
+        let size = resource_data(&data, revision).len();
+        Some(Candidate { settings, data, segmental_snr, size }).filter(|candidate| candidate.segmental_snr >= min_segmental_snr)
+    };
+
+    let mut best: Option<Candidate> = None;
+    let mut consider = |candidate: Candidate| {
+        // same size wins if it sounds better
+        let better = best.as_ref().is_none_or(|best| {
+            candidate.size < best.size || (candidate.size == best.size && candidate.segmental_snr > best.segmental_snr)
+        });
+        if better {
+            best = Some(candidate);
+        }
+    };
+
+    for (vad, highpass_filter) in [(false, false), (false, true), (true, false), (true, true)] {
+        let settings = |quality, complexity| EncoderSettings { quality, complexity, vad, highpass_filter, ..base.clone() };
+
+        // qualities from low up to high (exclusive) are left to check, and passing is what passed at high
+        let (mut low, mut high) = (0, 9);
+        let mut passing = None;
+        while low < high {
+            let quality = (low + high) / 2;
+            match try_settings(settings(quality, 10)) {
+                Some(candidate) => {
+                    high = quality;
+                    passing = Some(candidate);
+                }
+                None => low = quality + 1,
+            }
+        }
+        let Some(passing) = passing else {
+            continue;
+        };
+
+        let quality = passing.settings.quality;
+        consider(passing);
+        for complexity in 0..10 {
+            if let Some(candidate) = try_settings(settings(quality, complexity)) {
+                consider(candidate);
+            }
+        }
+    }
+
+    best.map(|candidate| (candidate.settings, candidate.data))
+}
+
+/// Average level of each of the report bands in dB, a full scale sine comes out at -3
+pub fn band_levels(samples: &[f32]) -> BandLevels {
+    let mut planner = RealFftPlanner::<f64>::new();
+    let fft = planner.plan_fft_forward(FFT_SIZE);
+    let mut input = fft.make_input_vec();
+    let mut spectrum = fft.make_output_vec();
+
+    let window: Vec<f64> = (0..FFT_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / FFT_SIZE as f64).cos())
+        .collect();
+    let window_energy: f64 = window.iter().map(|w| w * w).sum();
+
+    // half overlapping frames, the last one padded with silence
+    let mut power = [0.0; BANDS.len()];
+    let mut frame_count = 0;
+    for start in (0..samples.len().max(1)).step_by(FFT_SIZE / 2) {
+        input.fill(0.0);
+        for (i, sample) in samples[start..].iter().take(FFT_SIZE).enumerate() {
+            input[i] = *sample as f64 * window[i];
+        }
+        fft.process(&mut input, &mut spectrum).unwrap();
+        frame_count += 1;
+
+        for (bin, value) in spectrum.iter().enumerate() {
+            let freq = bin as f64 * SPEEX_SAMPLE_RATE as f64 / FFT_SIZE as f64;
+            // everything but dc and nyquist shows up twice in the full spectrum
+            let scale = if bin == 0 || bin == FFT_SIZE / 2 { 1.0 } else { 2.0 };
+            if let Some(band) = BANDS.iter().position(|(from, to)| freq >= *from && (freq < *to || *to == BANDS[BANDS.len() - 1].1)) {
+                power[band] += scale * value.norm_sqr() / (FFT_SIZE as f64 * window_energy);
+            }
+        }
+    }
+
+    // floored at -120 dB, silence would be -inf otherwise
+    power.map(|power| 10.0 * (power / frame_count as f64).max(1e-12).log10())
+}
+
+fn metrics(frames: &[FrameStats]) -> Metrics {
+    let signal_energy: f64 = frames.iter().map(|f| f.signal_energy).sum();
+    let noise_energy: f64 = frames.iter().map(|f| f.noise_energy).sum();
+
+    let active: Vec<&FrameStats> = frames.iter().filter(|f| f.active).collect();
+    let average = |values: Vec<f64>| match values.is_empty() {
+        true => None,
+        false => Some(values.iter().sum::<f64>() / values.len() as f64),
+    };
+
+    Metrics {
+        snr: snr(signal_energy, noise_energy),
+        segmental_snr: average(active.iter()
+            .map(|f| snr(f.signal_energy, f.noise_energy).clamp(MIN_SEGMENT_SNR, MAX_SEGMENT_SNR))
+            .collect()),
+        log_spectral_distance: average(active.iter().map(|f| f.log_spectral_distance).collect()),
+    }
+}
+
+fn snr(signal_energy: f64, noise_energy: f64) -> f64 {
+    // floored so silence against silence and perfect matches still come out as numbers
+    10.0 * (signal_energy.max(ENERGY_FLOOR) / noise_energy.max(ENERGY_FLOOR)).log10()
+}
+
+impl QualityReport {
+    pub fn print(&self) {
+        eprintln!("{:>8} {:>8} {:>8} {:>8}", "second", "snr", "seg snr", "lsd");
+        for (second, metrics) in self.per_second.iter().enumerate() {
+            eprintln!("{:>8} {}", second, metrics.row());
+        }
+        eprintln!("{:>8} {}", "overall", self.overall.row());
+
+        eprintln!();
+        let names: Vec<String> = self.band_levels.iter().map(|(name, _)| format!("{name:>12}")).collect();
+        eprintln!("{:>14} {}", "band (dB)", names.join(" "));
+        for (band, (from, to)) in BANDS.iter().enumerate() {
+            let levels: Vec<String> = self.band_levels.iter().map(|(_, levels)| format!("{:>12.1}", levels[band])).collect();
+            eprintln!("{:>14} {}", format!("{from}-{to} Hz"), levels.join(" "));
+        }
+    }
+}
+
+impl Metrics {
+    fn row(&self) -> String {
+        let format = |value: Option<f64>| match value {
+            Some(value) => format!("{value:>8.2}"),
+            None => format!("{:>8}", "-"),
+        };
+        format!("{} {} {}", format(Some(self.snr)), format(self.segmental_snr), format(self.log_spectral_distance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{auto_tune, band_levels, measure, measure_encoded, MAX_SEGMENT_SNR};
+    use crate::encoding::{encode_data, EncoderSettings};
+    use crate::resource_parse::ResrcRevision;
+    use crate::test_util::{sine, tone_then_silence};
+
+    #[test]
+    fn snr_stays_finite() {
+        let silence = vec![0.0; 8000];
+        let report = measure(&silence, &silence);
+        assert_eq!(report.overall.snr, 0.0);
+        assert_eq!(report.overall.segmental_snr, None);
+
+        let tone = sine(440.0, 0.5, 8000);
+        let report = measure(&tone, &tone);
+        assert!(report.overall.snr.is_finite());
+        assert_eq!(report.overall.segmental_snr, Some(MAX_SEGMENT_SNR));
+
+        let report = measure(&tone, &silence);
+        assert!(report.overall.snr.abs() < 1e-6);
+    }
+
+    #[test]
+    fn auto_tune_picks_the_lowest_passing_quality() {
+        let input = tone_then_silence();
+        let revision = ResrcRevision { head: 0x33e, branch_id: 0, branch_revision: 0 };
+        let (settings, data) = auto_tune(&input, &EncoderSettings::default(), &revision, 15.0).unwrap();
+        assert!(data == encode_data(input.clone(), &settings));
+
+        let segmental_snr = |settings: &EncoderSettings| {
+            let data = encode_data(input.clone(), settings);
+            measure_encoded(&input, &data, settings).overall.segmental_snr.unwrap()
+        };
+        assert!(segmental_snr(&settings) >= 15.0);
+        assert!(settings.quality > 0);
+        assert!(segmental_snr(&EncoderSettings { quality: settings.quality - 1, complexity: 10, ..settings.clone() }) < 15.0);
+    }
+
+    #[test]
+    fn band_levels_of_a_sine() {
+        // right in the middle of the 1000-2000 Hz band, which is the only one it should show up in
+        let levels = band_levels(&sine(1500.0, 1.0, 8000));
+        assert!((levels[2] + 3.0).abs() < 0.1, "{levels:?}");
+        for (band, level) in levels.iter().enumerate() {
+            if band != 2 {
+                // not more because of the splatter where the input stops halfway through the last frames
+                assert!(*level < -40.0, "{levels:?}");
+            }
+        }
+
+        assert_eq!(band_levels(&[0.0; 8000]), [-120.0; 5]);
+        assert_eq!(band_levels(&[]), [-120.0; 5]);
+    }
+}
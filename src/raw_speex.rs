@@ -0,0 +1,175 @@
+use std::{fs::File, io::{BufWriter, Cursor, Read, Write}, path::{Path, PathBuf}};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use speex_safe::{ModeId, NbSubmodeId};
+
+use crate::decoding::Frame;
+use crate::ogg_speex::{decode_packets, repackage_frames, SpeexImport};
+use crate::{submode_bits_per_frame, SPEEX_SAMPLE_RATE};
+
+// raw streams are laid out like the ones from speex's sampleenc/sampledec examples:
+// each frame is preceded by its length as a 32-bit integer
+
+// no narrowband frame is bigger than one in the highest submode
+const MAX_FRAME_SIZE: usize = (submode_bits_per_frame(NbSubmodeId::ExtremeHigh) as usize + 7) >> 3;
+
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    frames: Vec<FrameInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FrameInfo {
+    /// The whole flags byte, this is what gets written back to the vop
+    flags: u8,
+    // the rest is just for people reading the file
+    submode: u8,
+    speech: bool,
+}
+
+/// The flags sidecar lives next to the raw stream, e.g. `voice.raw.json` for `voice.raw`
+pub fn sidecar_path(raw_path: &Path) -> PathBuf {
+    let mut path = raw_path.as_os_str().to_owned();
+    path.push(".json");
+    PathBuf::from(path)
+}
+
+/// Writes the speex frames of a vop into a raw stream, and their flags into a json sidecar
+pub fn export_raw(frames: &[Frame], output: &Path) {
+    let mut out = BufWriter::new(File::create(output).unwrap());
+    for frame in frames {
+        out.write_u32::<LittleEndian>(frame.data.len() as u32).unwrap();
+        out.write_all(frame.data).unwrap();
+    }
+    out.flush().unwrap();
+
+    let sidecar = Sidecar {
+        frames: frames.iter().map(|frame| FrameInfo {
+            flags: frame.flags,
+            submode: frame.flags & 0x3F,
+            speech: (frame.flags & 0x80) != 0,
+        }).collect(),
+    };
+    let sidecar_file = File::create(sidecar_path(output)).unwrap();
+    serde_json::to_writer_pretty(BufWriter::new(sidecar_file), &sidecar).unwrap();
+}
+
+/// Reads a raw speex stream, along with its flags sidecar if there is one.
+///
+/// Works the same way as [`crate::ogg_speex::import_ogg`] otherwise.
+pub fn import_raw(input: &Path, reencode: bool) -> Result<SpeexImport, String> {
+    let mut data = Vec::new();
+    File::open(input).and_then(|mut file| file.read_to_end(&mut data)).map_err(|e| e.to_string())?;
+
+    let mut packets = Vec::new();
+    let mut data = Cursor::new(data);
+    while (data.position() as usize) < data.get_ref().len() {
+        let len = data.read_u32::<LittleEndian>().map_err(|_| "stream ends in the middle of a frame length")? as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(format!("frame {} is {len} bytes, which is too big for a speex frame", packets.len()));
+        }
+        let mut packet = vec![0u8; len];
+        data.read_exact(&mut packet).map_err(|_| "stream ends in the middle of a frame")?;
+        packets.push(packet);
+    }
+
+    let flags = match File::open(sidecar_path(input)) {
+        Ok(file) => {
+            let sidecar: Sidecar = serde_json::from_reader(file).map_err(|e| format!("invalid flags sidecar: {e}"))?;
+            if sidecar.frames.len() != packets.len() {
+                return Err(format!(
+                    "flags sidecar has {} frames, but the stream has {}",
+                    sidecar.frames.len(),
+                    packets.len(),
+                ));
+            }
+            Some(sidecar.frames.iter().map(|frame| frame.flags).collect::<Vec<_>>())
+        }
+        Err(_) => {
+            eprintln!("warning: no flags sidecar at {}, guessing the flags", sidecar_path(input).display());
+            None
+        }
+    };
+
+    match repackage_frames(&packets, flags.as_deref()) {
+        Ok(frames) => Ok(SpeexImport::Frames(frames)),
+        Err(problem) if reencode => {
+            eprintln!("{problem}, re-encoding");
+            Ok(SpeexImport::Samples(decode_packets(&packets, ModeId::NarrowBand, 1, SPEEX_SAMPLE_RATE)))
+        }
+        Err(problem) => Err(format!("{problem}, use --reencode to re-encode it")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::Write, path::Path};
+
+    use byteorder::{LittleEndian, WriteBytesExt};
+
+    use super::{export_raw, import_raw, sidecar_path};
+    use crate::decoding::read_frames;
+    use crate::encoding::{encode_data, EncoderSettings};
+    use crate::ogg_speex::SpeexImport;
+    use crate::test_util::{temp_path, tone_then_silence};
+
+    fn vop_data() -> Vec<u8> {
+        encode_data(tone_then_silence(), &EncoderSettings { vad: true, ..Default::default() })
+    }
+
+    fn import_and_clean_up(path: &Path) -> Result<SpeexImport, String> {
+        let imported = import_raw(path, false);
+        std::fs::remove_file(path).unwrap();
+        let _ = std::fs::remove_file(sidecar_path(path));
+        imported
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = vop_data();
+        let (frames, _) = read_frames(&data, false).unwrap();
+        assert!(frames.iter().any(|frame| frame.flags != frames[0].flags));
+
+        let path = temp_path("round_trip.raw");
+        export_raw(&frames, &path);
+        match import_and_clean_up(&path).unwrap() {
+            SpeexImport::Frames(imported) => assert!(imported == data[frames[0].offset..]),
+            SpeexImport::Samples(_) => panic!("frames were re-encoded"),
+        }
+    }
+
+    #[test]
+    fn mismatched_sidecar() {
+        let data = vop_data();
+        let (frames, _) = read_frames(&data, false).unwrap();
+
+        let path = temp_path("mismatched_sidecar.raw");
+        export_raw(&frames, &path);
+
+        let mut sidecar: serde_json::Value = serde_json::from_reader(File::open(sidecar_path(&path)).unwrap()).unwrap();
+        let flags = &mut sidecar["frames"][3]["flags"];
+        let submode = flags.as_u64().unwrap() & 0x3F;
+        *flags = (submode % 8 + 1).into();
+        serde_json::to_writer(File::create(sidecar_path(&path)).unwrap(), &sidecar).unwrap();
+
+        let problem = import_and_clean_up(&path).err().unwrap();
+        assert!(problem.starts_with("packet 3 "), "{problem}");
+    }
+
+    #[test]
+    fn bad_inputs() {
+        assert!(import_raw(&temp_path("missing.raw"), false).is_err());
+
+        // a corrupt length shouldn't turn into a 4 GB allocation
+        let path = temp_path("huge_frame.raw");
+        File::create(&path).unwrap().write_u32::<LittleEndian>(u32::MAX).unwrap();
+        assert!(import_and_clean_up(&path).is_err());
+
+        let path = temp_path("truncated.raw");
+        let mut file = File::create(&path).unwrap();
+        file.write_u32::<LittleEndian>(20).unwrap();
+        file.write_all(&[0; 10]).unwrap();
+        assert!(import_and_clean_up(&path).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fs::File, io::{Read, Seek, SeekFrom, Write}};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 use byteorder::{BigEndian, ReadBytesExt};
 use miniz_oxide::inflate::core::{decompress, inflate_flags::{TINFL_FLAG_PARSE_ZLIB_HEADER, TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF}, DecompressorOxide};
@@ -28,7 +28,7 @@ pub enum ResrcMethod {
 }
 
 impl Resrc {
-    pub fn new(res: &mut File) -> Self {
+    pub fn new<R: Read + Seek>(res: &mut R) -> Self {
         let mut resrc_type = [0u8; 3];
         res.read_exact(&mut resrc_type).unwrap();
 
@@ -71,7 +71,11 @@ impl Resrc {
                     let current_pos = res.stream_position().unwrap() as u32;
                     let size = match dep_table_offset {
                         Some(offset) => offset - current_pos,
-                        None => res.metadata().unwrap().len() as u32 - current_pos,
+                        None => {
+                            let end = res.seek(SeekFrom::End(0)).unwrap() as u32;
+                            res.seek(SeekFrom::Start(current_pos as u64)).unwrap();
+                            end - current_pos
+                        }
                     };
                     let mut data_vec = vec![0u8; size as usize];
                     res.read_exact(&mut data_vec).unwrap();
@@ -95,7 +99,7 @@ impl Resrc {
     }
 }
 
-fn zlib_decompress(res: &mut File) -> Vec<u8> {
+fn zlib_decompress<R: Read + Seek>(res: &mut R) -> Vec<u8> {
     res.seek(SeekFrom::Current(2)).unwrap(); // unused i16, always 0x0001
     let num_chunks = res.read_u16::<BigEndian>().unwrap();
 
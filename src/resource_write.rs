@@ -1,12 +1,19 @@
-use std::{fs::File, io::{Seek, Write}, path::Path};
+use std::{io::{Cursor, Seek, Write}, path::Path};
 
 use byteorder::{BigEndian, WriteBytesExt};
 use miniz_oxide::deflate::compress_to_vec_zlib;
 
 use crate::resource_parse::ResrcRevision;
+use crate::write_output;
 
 pub fn write_resource(path: &Path, data: Vec<u8>, rev: ResrcRevision) {
-    let mut out = File::create(path).unwrap();
+    write_output(path, &resource_data(&data, &rev));
+}
+
+/// Builds the whole resource file in memory, which is what [`write_resource`] writes
+pub fn resource_data(data: &[u8], rev: &ResrcRevision) -> Vec<u8> {
+    // the dependency table offset has to be patched in later, and stdout can't seek
+    let mut out = Cursor::new(Vec::new());
 
     // resource header crap
 
@@ -75,4 +82,6 @@ pub fn write_resource(path: &Path, data: Vec<u8>, rev: ResrcRevision) {
         out.seek(std::io::SeekFrom::Start(8)).unwrap();
         out.write_u32::<BigEndian>(dep_table_offset as u32).unwrap();
     }
-}
\ No newline at end of file
+
+    out.into_inner()
+}
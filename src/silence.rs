@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use crate::decoding::{VopDecoder, VopError};
+use crate::{submode_bits_per_frame, SAMPLE_COUNT, SPEEX_SAMPLE_RATE};
+
+// loudness gets checked in 10 ms windows, single samples are too jumpy
+const WINDOW: usize = SPEEX_SAMPLE_RATE as usize / 100;
+
+#[derive(Clone, Copy)]
+pub struct SilenceOptions {
+    /// Anything quieter than this (dBFS) counts as silence
+    pub threshold: f64,
+    /// Silence shorter than this doesn't get cut
+    pub min_duration: Duration,
+    /// How much silence to leave in on each end
+    pub padding: Duration,
+}
+
+/// How many samples could be cut from the start and the end, padding already taken into account
+pub fn silent_edges(samples: &[f32], options: &SilenceOptions) -> (usize, usize) {
+    let threshold = 10f64.powf(options.threshold / 20.0);
+    let loud = |window: &[f32]| {
+        let rms = (window.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / window.len() as f64).sqrt();
+        rms > threshold
+    };
+
+    let to_samples = |duration: Duration| (duration.as_secs_f64() * SPEEX_SAMPLE_RATE as f64) as usize;
+    let min_duration = to_samples(options.min_duration);
+    let padding = to_samples(options.padding);
+    let cut = |silence: usize| match silence >= min_duration {
+        true => silence.saturating_sub(padding),
+        false => 0,
+    };
+
+    let windows: Vec<bool> = samples.chunks(WINDOW).map(loud).collect();
+    let Some(first_loud) = windows.iter().position(|loud| *loud) else {
+        // nothing but silence, it all counts as leading so the padding is all that's left
+        return (cut(samples.len()), 0);
+    };
+    let last_loud = windows.iter().rposition(|loud| *loud).unwrap();
+
+    let leading = first_loud * WINDOW;
+    let trailing = samples.len().saturating_sub((last_loud + 1) * WINDOW);
+
+    (cut(leading), cut(trailing))
+}
+
+fn seconds(samples: usize) -> f64 {
+    samples as f64 / SPEEX_SAMPLE_RATE as f64
+}
+
+/// Cuts the silence off the start and the end of 8 kHz samples
+pub fn trim_silence(mut samples: Vec<f32>, options: &SilenceOptions) -> Vec<f32> {
+    let (leading, trailing) = silent_edges(&samples, options);
+    eprintln!("trimmed {:.2} s of silence from the start and {:.2} s from the end", seconds(leading), seconds(trailing));
+
+    samples.truncate(samples.len() - trailing);
+    samples.drain(..leading);
+    samples
+}
+
+/// Prints how much silence could be cut from an existing vop
+pub fn analyze_vop(data: &[u8], options: &SilenceOptions) -> Result<(), VopError> {
+    let mut samples = Vec::new();
+    let mut frame_sizes = Vec::new();
+    for frame in VopDecoder::new(data, false)? {
+        let frame = frame?;
+        samples.extend_from_slice(&frame.samples);
+        // plus one for the flags byte
+        frame_sizes.push(((submode_bits_per_frame(frame.submode) as usize + 7) >> 3) + 1);
+    }
+
+    let (leading, trailing) = silent_edges(&samples, options);
+    // only whole frames can go
+    let leading_frames = leading / SAMPLE_COUNT;
+    let trailing_frames = (trailing / SAMPLE_COUNT).min(frame_sizes.len() - leading_frames);
+    let cut_bytes: usize = frame_sizes[..leading_frames].iter().sum::<usize>()
+        + frame_sizes[frame_sizes.len() - trailing_frames..].iter().sum::<usize>();
+
+    println!("length: {:.2} s ({} frames, {} bytes)", seconds(samples.len()), frame_sizes.len(), frame_sizes.iter().sum::<usize>());
+    println!("silence at the start: {:.2} s ({leading_frames} frames)", seconds(leading_frames * SAMPLE_COUNT));
+    println!("silence at the end: {:.2} s ({trailing_frames} frames)", seconds(trailing_frames * SAMPLE_COUNT));
+    println!("{} frames ({cut_bytes} bytes) could be cut", leading_frames + trailing_frames);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{silent_edges, trim_silence, SilenceOptions};
+    use crate::test_util::sine;
+
+    fn options(min_duration: f64, padding: f64) -> SilenceOptions {
+        SilenceOptions {
+            threshold: -50.0,
+            min_duration: Duration::from_secs_f64(min_duration),
+            padding: Duration::from_secs_f64(padding),
+        }
+    }
+
+    /// Half a second of silence, a second of a tone and three quarters of a second of silence
+    fn padded_tone() -> Vec<f32> {
+        let mut samples = vec![0.0; 4000];
+        samples.extend(sine(440.0, 0.5, 8000));
+        samples.resize(18000, 0.0);
+        samples
+    }
+
+    #[test]
+    fn finds_both_edges() {
+        assert_eq!(silent_edges(&padded_tone(), &options(0.1, 0.0)), (4000, 6000));
+        assert_eq!(trim_silence(padded_tone(), &options(0.1, 0.0)).len(), 8000);
+    }
+
+    #[test]
+    fn leaves_padding() {
+        assert_eq!(silent_edges(&padded_tone(), &options(0.1, 0.05)), (3600, 5600));
+        assert_eq!(trim_silence(padded_tone(), &options(0.1, 0.05)).len(), 8800);
+    }
+
+    #[test]
+    fn keeps_short_silence() {
+        assert_eq!(silent_edges(&padded_tone(), &options(0.6, 0.0)), (0, 6000));
+        assert_eq!(silent_edges(&padded_tone(), &options(1.0, 0.0)), (0, 0));
+    }
+
+    #[test]
+    fn all_silent() {
+        let silence = vec![0.0; 8000];
+        assert_eq!(silent_edges(&silence, &options(0.1, 0.0)), (8000, 0));
+        assert_eq!(silent_edges(&silence, &options(0.1, 0.05)), (7600, 0));
+        assert_eq!(silent_edges(&silence, &options(2.0, 0.05)), (0, 0));
+        assert_eq!(trim_silence(silence, &options(0.1, 0.05)).len(), 400);
+    }
+}
@@ -0,0 +1,40 @@
+//! Helpers shared by the unit tests
+
+use std::{f32::consts::PI, path::PathBuf};
+
+use crate::generate::Random;
+use crate::SPEEX_SAMPLE_RATE;
+
+/// `len` samples of a sine at 8 kHz
+pub fn sine(frequency: f32, amplitude: f32, len: usize) -> Vec<f32> {
+    (0..len).map(|i| amplitude * (2.0 * PI * frequency * i as f32 / SPEEX_SAMPLE_RATE as f32).sin()).collect()
+}
+
+/// `len` samples of uniform white noise, the same every time
+pub fn white_noise(amplitude: f32, len: usize) -> Vec<f32> {
+    let mut random = Random::new(1);
+    (0..len).map(|_| amplitude * random.next() as f32).collect()
+}
+
+/// Amplitude of the sine at `frequency` in the samples, best with whole periods
+pub fn tone_amplitude(samples: &[f32], frequency: f32) -> f32 {
+    let (mut sin, mut cos) = (0.0, 0.0);
+    for (i, sample) in samples.iter().enumerate() {
+        let phase = 2.0 * PI * frequency * i as f32 / SPEEX_SAMPLE_RATE as f32;
+        sin += sample * phase.sin();
+        cos += sample * phase.cos();
+    }
+    2.0 * (sin * sin + cos * cos).sqrt() / samples.len() as f32
+}
+
+/// Half a second of a tone and half a second of silence, so vad switches submodes halfway through
+pub fn tone_then_silence() -> Vec<f32> {
+    let mut samples = sine(440.0, 0.5, 4000);
+    samples.resize(8000, 0.0);
+    samples
+}
+
+/// A path in the temp dir that no other test (or test run) uses
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("voiptool-{}-{name}", std::process::id()))
+}
@@ -0,0 +1,218 @@
+use std::{f64::consts::PI, time::Duration};
+
+use realfft::{num_complex::Complex, RealFftPlanner};
+
+use crate::input_decoding::{resample, ResamplerQuality};
+use crate::SPEEX_SAMPLE_RATE;
+
+// 64 ms frames, long enough to resolve the harmonics of low voices
+const FFT_SIZE: usize = 512;
+const HOP: usize = FFT_SIZE / 4;
+// hann analysis and synthesis windows at 75% overlap add up to this
+const WINDOW_GAIN: f64 = 1.5;
+
+// the spectral envelope is a running max over the harmonics (wide enough to always catch one for voices
+// over ~120 Hz), smoothed out in the log domain. cepstral smoothing sinks into the gaps between harmonics
+const ENVELOPE_PEAK_BINS: usize = 8;
+const ENVELOPE_SMOOTHING_BINS: usize = 8;
+
+#[derive(Clone, Copy)]
+pub enum Stretch {
+    /// 2.0 makes it twice as long
+    Ratio(f64),
+    /// Stretch or squash to this length
+    Fit(Duration),
+}
+
+#[derive(Clone, Copy)]
+pub struct PitchTimeOptions {
+    /// Pitch shift in semitones
+    pub semitones: f64,
+    /// Keep the spectral envelope where it is, so shifted voices don't sound like chipmunks or giants
+    pub preserve_formants: bool,
+    pub stretch: Option<Stretch>,
+    /// Used for getting pitch shifted audio back to its length
+    pub resampler: ResamplerQuality,
+}
+
+fn principal_argument(phase: f64) -> f64 {
+    phase - 2.0 * PI * (phase / (2.0 * PI)).round()
+}
+
+/// Shifts the pitch and changes the length of 8 kHz samples independently of each other.
+///
+/// The phase vocoder only changes the length, pitch shifting stretches by the pitch ratio on top
+/// and then resamples back by the same ratio.
+pub fn pitch_time(samples: &[f32], options: &PitchTimeOptions) -> Vec<f32> {
+    let length = samples.len() as f64 / SPEEX_SAMPLE_RATE as f64;
+    let stretch = match options.stretch {
+        Some(Stretch::Ratio(ratio)) => ratio,
+        Some(Stretch::Fit(duration)) if length > 0.0 => duration.as_secs_f64() / length,
+        _ => 1.0,
+    };
+    let pitch = 2f64.powf(options.semitones / 12.0);
+
+    if samples.is_empty() || (stretch == 1.0 && pitch == 1.0) {
+        return samples.to_vec();
+    }
+
+    let formant_shift = options.preserve_formants.then_some(pitch);
+    let mut output = vocode(samples, stretch * pitch, formant_shift);
+    if pitch != 1.0 {
+        // playing it back faster by the pitch ratio gets it back to the stretched length
+        output = resample(output, (SPEEX_SAMPLE_RATE as f64 * pitch).round() as u32, options.resampler);
+    }
+
+    if options.semitones != 0.0 {
+        eprintln!("shifted the pitch by {} semitones", options.semitones);
+    }
+    if stretch != 1.0 {
+        eprintln!("stretched {:.2} s to {:.2} s", length, output.len() as f64 / SPEEX_SAMPLE_RATE as f64);
+    }
+
+    output
+}
+
+fn spectral_envelope(magnitudes: &[f64], envelope: &mut [f64]) {
+    let around = |bin: usize, width: usize| bin.saturating_sub(width)..(bin + width + 1).min(magnitudes.len());
+    let peaks: Vec<f64> = (0..magnitudes.len())
+        .map(|bin| magnitudes[around(bin, ENVELOPE_PEAK_BINS)].iter().fold(1e-9, |peak, magnitude| magnitude.max(peak)).ln())
+        .collect();
+    for (bin, envelope) in envelope.iter_mut().enumerate() {
+        let range = around(bin, ENVELOPE_SMOOTHING_BINS);
+        *envelope = (peaks[range.clone()].iter().sum::<f64>() / range.len() as f64).exp();
+    }
+}
+
+/// Changes the length without touching the pitch. With `formant_shift` the spectral envelope gets
+/// warped the opposite way of what resampling by that ratio will do to it afterwards.
+fn vocode(samples: &[f32], stretch: f64, formant_shift: Option<f64>) -> Vec<f32> {
+    let mut planner = RealFftPlanner::<f64>::new();
+    let forward = planner.plan_fft_forward(FFT_SIZE);
+    let inverse = planner.plan_fft_inverse(FFT_SIZE);
+    let mut frame = forward.make_input_vec();
+    let mut spectrum = forward.make_output_vec();
+
+    let window: Vec<f64> = (0..FFT_SIZE)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / FFT_SIZE as f64).cos())
+        .collect();
+    let bins = spectrum.len();
+    let bin_frequency = |bin: usize| 2.0 * PI * bin as f64 / FFT_SIZE as f64;
+
+    // frames are read every HOP / stretch samples and written every HOP samples
+    let analysis_hop = HOP as f64 / stretch;
+    let output_len = (samples.len() as f64 * stretch).round() as usize;
+    let frame_count = output_len / HOP + FFT_SIZE / HOP + 1;
+
+    let mut output = vec![0.0f64; frame_count * HOP + FFT_SIZE];
+    let mut magnitudes = vec![0.0; bins];
+    let mut frequencies = vec![0.0; bins];
+    let mut envelope = vec![0.0; bins];
+    let mut previous_phases = vec![0.0; bins];
+    let mut phases = vec![0.0; bins];
+    let mut previous_start = None;
+
+    for m in 0..frame_count {
+        // frames are centered on their position, so the first one starts before the input
+        let start = (m as f64 * analysis_hop).round() as isize - FFT_SIZE as isize / 2;
+        for (i, value) in frame.iter_mut().enumerate() {
+            let index = start + i as isize;
+            let sample = match index >= 0 {
+                true => samples.get(index as usize).copied().unwrap_or(0.0),
+                false => 0.0,
+            };
+            *value = sample as f64 * window[i];
+        }
+        forward.process(&mut frame, &mut spectrum).unwrap();
+
+        // the real frequency of each bin, from how far its phase moved since the last frame
+        let hop = previous_start.map_or(analysis_hop, |previous| (start - previous) as f64);
+        for (bin, value) in spectrum.iter().enumerate() {
+            let phase = value.arg();
+            magnitudes[bin] = value.norm();
+            frequencies[bin] = match hop > 0.0 {
+                true => {
+                    let deviation = principal_argument(phase - previous_phases[bin] - bin_frequency(bin) * hop);
+                    bin_frequency(bin) + deviation / hop
+                }
+                false => bin_frequency(bin),
+            };
+            previous_phases[bin] = phase;
+        }
+
+        if let Some(shift) = formant_shift {
+            spectral_envelope(&magnitudes, &mut envelope);
+
+            // whatever ends up at bin * shift after resampling gets the envelope that was there originally
+            for bin in 0..bins {
+                let target = (bin as f64 * shift).min((bins - 1) as f64);
+                let lower = (target.floor() as usize).min(bins - 2);
+                let fraction = target - lower as f64;
+                let target_envelope = envelope[lower] * (1.0 - fraction) + envelope[lower + 1] * fraction;
+                magnitudes[bin] *= target_envelope / envelope[bin];
+            }
+        }
+
+        for bin in 0..bins {
+            phases[bin] = match previous_start {
+                Some(_) => phases[bin] + frequencies[bin] * HOP as f64,
+                None => previous_phases[bin],
+            };
+            spectrum[bin] = Complex::from_polar(magnitudes[bin], phases[bin]);
+        }
+        previous_start = Some(start);
+
+        // realfft wants these to be purely real
+        spectrum[0].im = 0.0;
+        spectrum[bins - 1].im = 0.0;
+
+        inverse.process(&mut spectrum, &mut frame).unwrap();
+        for (i, value) in frame.iter().enumerate() {
+            output[m * HOP + i] += value * window[i] / (FFT_SIZE as f64 * WINDOW_GAIN);
+        }
+    }
+
+    // output frames are centered the same way as the input ones
+    output[FFT_SIZE / 2..FFT_SIZE / 2 + output_len].iter().map(|sample| *sample as f32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{pitch_time, PitchTimeOptions, Stretch};
+    use crate::test_util::{sine, tone_amplitude};
+    use crate::SAMPLE_COUNT;
+
+    fn options(semitones: f64, stretch: Option<Stretch>) -> PitchTimeOptions {
+        PitchTimeOptions { semitones, preserve_formants: false, stretch, resampler: Default::default() }
+    }
+
+    /// Frequency of the loudest sine, to the nearest 5 Hz
+    fn peak_frequency(samples: &[f32]) -> f32 {
+        (20..800).map(|step| step as f32 * 5.0)
+            .max_by(|a, b| tone_amplitude(samples, *a).total_cmp(&tone_amplitude(samples, *b)))
+            .unwrap()
+    }
+
+    #[test]
+    fn fits_the_length() {
+        let input = sine(440.0, 0.5, 8000);
+        for seconds in [0.6, 1.5] {
+            let output = pitch_time(&input, &options(0.0, Some(Stretch::Fit(Duration::from_secs_f64(seconds)))));
+            let expected = (seconds * 8000.0) as usize;
+            assert!(output.len().abs_diff(expected) <= SAMPLE_COUNT, "{} instead of {expected}", output.len());
+            assert!((peak_frequency(&output[1000..output.len() - 1000]) - 440.0).abs() <= 5.0);
+        }
+    }
+
+    #[test]
+    fn shifts_by_an_octave() {
+        let input = sine(440.0, 0.5, 8000);
+        for (semitones, frequency) in [(12.0, 880.0), (-12.0, 220.0)] {
+            let output = pitch_time(&input, &options(semitones, None));
+            assert_eq!(output.len(), input.len());
+            assert!((peak_frequency(&output[1000..7000]) - frequency).abs() <= 5.0);
+        }
+    }
+}